@@ -0,0 +1,54 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The integration with libtest, like `coppers::runner`, relies on unstable features of the
+// compiler. Those parts are only compiled with a nightly compiler, which is marked with the
+// `coppers_nightly` cfg. Everything else works on stable as well.
+//
+// The compiler, target, profile and features are recorded with the results of every run, and
+// are only known at build time.
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(coppers_nightly)");
+    println!("cargo:rerun-if-env-changed=RUSTC");
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+        .unwrap_or_default();
+    // Compilers that are built from source are called `dev`, and support unstable features too
+    if version.contains("nightly") || version.contains("-dev") {
+        println!("cargo:rustc-cfg=coppers_nightly");
+    }
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| Some(name.strip_prefix("CARGO_FEATURE_")?.to_lowercase()))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=COPPERS_RUSTC_VERSION={}", version.trim());
+    println!(
+        "cargo:rustc-env=COPPERS_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+    println!(
+        "cargo:rustc-env=COPPERS_PROFILE={}",
+        env::var("PROFILE").unwrap_or_default()
+    );
+    println!("cargo:rustc-env=COPPERS_FEATURES={}", features.join(","));
+}
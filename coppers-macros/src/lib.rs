@@ -0,0 +1,255 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote, ToTokens};
+use syn::meta::ParseNestedMeta;
+use syn::parse::Parser;
+use syn::{parse_macro_input, Attribute, Expr, ExprLit, ItemFn, Lit, LitInt, LitStr, Meta};
+
+// Turns a function into a regular `#[test]` function, and registers it together with its
+// settings with the Coppers runner. For example
+//
+// #[coppers::test(repeats = 50, warmup = 3, budget_uj = 5_000, group = "parser")]
+// fn parse_large_file() { ... }
+#[proc_macro_attribute]
+pub fn test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let function = parse_macro_input!(item as ItemFn);
+    match parse_settings(attr.into()) {
+        Ok(settings) => expand(&settings, function).into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+#[derive(Default, Debug, PartialEq)]
+struct Settings {
+    repeats: Option<usize>,
+    warmup: Option<usize>,
+    budget_uj: Option<u128>,
+    group: Option<String>,
+}
+
+fn parse_settings(attr: TokenStream2) -> syn::Result<Settings> {
+    let mut settings = Settings::default();
+    let parser = syn::meta::parser(|meta| settings.parse(meta));
+    parser.parse2(attr)?;
+    Ok(settings)
+}
+
+impl Settings {
+    fn parse(&mut self, meta: ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("repeats") {
+            let repeats = meta.value()?.parse::<LitInt>()?.base10_parse()?;
+            if repeats == 0 {
+                return Err(meta.error("a test needs to be repeated at least once"));
+            }
+            self.repeats = Some(repeats);
+        } else if meta.path.is_ident("warmup") {
+            self.warmup = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+        } else if meta.path.is_ident("budget_uj") {
+            self.budget_uj = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+        } else if meta.path.is_ident("group") {
+            self.group = Some(meta.value()?.parse::<LitStr>()?.value());
+        } else {
+            return Err(
+                meta.error("unknown setting, expected `repeats`, `warmup`, `budget_uj` or `group`")
+            );
+        }
+        Ok(())
+    }
+}
+
+fn expand(settings: &Settings, mut function: ItemFn) -> TokenStream2 {
+    let name = function.sig.ident.clone();
+    let repeats = option(&settings.repeats);
+    let warmup = option(&settings.warmup);
+    let budget_uj = option(&settings.budget_uj);
+    let group = option(&settings.group);
+
+    let ignore = function
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("ignore"));
+    let should_panic = match function
+        .attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("should_panic"))
+    {
+        Some(attr) => match expected_message(attr) {
+            Some(msg) => quote!(::coppers::__private::ShouldPanic::YesWithMessage(#msg)),
+            None => quote!(::coppers::__private::ShouldPanic::Yes),
+        },
+        None => quote!(::coppers::__private::ShouldPanic::No),
+    };
+
+    // Functions that are marked with `#[test]` are removed when libtest does not collect the
+    // tests, like in a test target with `harness = false`. The function itself is registered
+    // under a hidden name, and a `#[test]` function with its original name and test attributes
+    // calls it, so libtest runs it as well. Attributes that decide whether the function exists
+    // at all apply to both.
+    let test_attrs: Vec<Attribute> = function
+        .attrs
+        .iter()
+        .filter(|attr| is_test_attr(attr) || is_cfg(attr))
+        .cloned()
+        .collect();
+    function.attrs.retain(|attr| !is_test_attr(attr));
+    function.sig.ident = format_ident!("__coppers_{}", name);
+    let registered = &function.sig.ident;
+    let vis = &function.vis;
+    let output = &function.sig.output;
+
+    quote! {
+        #[test]
+        #(#test_attrs)*
+        #vis fn #name() #output {
+            #registered()
+        }
+
+        // Clippy is more lenient towards `#[test]` functions, which the registered function is not
+        #[doc(hidden)]
+        #[allow(clippy::all)]
+        #function
+
+        ::coppers::__private::inventory::submit! {
+            ::coppers::__private::TestSettings {
+                module_path: ::core::module_path!(),
+                name: ::core::stringify!(#name),
+                repeats: #repeats,
+                warmup: #warmup,
+                budget_uj: #budget_uj,
+                group: #group,
+                ignore: #ignore,
+                should_panic: #should_panic,
+                function: || ::coppers::__private::TestReturn::into_result(#registered()),
+            }
+        }
+    }
+}
+
+// Attributes that libtest reads from a `#[test]` function
+fn is_test_attr(attr: &Attribute) -> bool {
+    attr.path().is_ident("ignore") || attr.path().is_ident("should_panic")
+}
+
+fn is_cfg(attr: &Attribute) -> bool {
+    attr.path().is_ident("cfg") || attr.path().is_ident("cfg_attr")
+}
+
+// The message of `#[should_panic(expected = "...")]` or `#[should_panic = "..."]`
+fn expected_message(attr: &Attribute) -> Option<LitStr> {
+    match &attr.meta {
+        Meta::NameValue(meta) => match &meta.value {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(msg), ..
+            }) => Some(msg.clone()),
+            _ => None,
+        },
+        Meta::List(_) => {
+            let mut expected = None;
+            let _ = attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("expected") {
+                    expected = Some(meta.value()?.parse::<LitStr>()?);
+                }
+                Ok(())
+            });
+            expected
+        }
+        Meta::Path(_) => None,
+    }
+}
+
+fn option<T: ToTokens>(value: &Option<T>) -> TokenStream2 {
+    match value {
+        Some(value) => quote!(::core::option::Option::Some(#value)),
+        None => quote!(::core::option::Option::None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand, expected_message, parse_settings, Settings};
+    use quote::quote;
+    use syn::Attribute;
+
+    #[test]
+    fn parse_settings_empty() {
+        assert_eq!(parse_settings(quote!()).unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn parse_settings_all() {
+        let settings = parse_settings(quote!(
+            repeats = 50,
+            warmup = 3,
+            budget_uj = 5_000,
+            group = "parser"
+        ))
+        .unwrap();
+        assert_eq!(
+            settings,
+            Settings {
+                repeats: Some(50),
+                warmup: Some(3),
+                budget_uj: Some(5000),
+                group: Some("parser".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_settings_unknown() {
+        assert!(parse_settings(quote!(iterations = 5)).is_err());
+    }
+
+    #[test]
+    fn parse_settings_invalid_value() {
+        assert!(parse_settings(quote!(repeats = "many")).is_err());
+        assert!(parse_settings(quote!(repeats = 0)).is_err());
+        assert!(parse_settings(quote!(group = 5)).is_err());
+    }
+
+    fn expected(attr: Attribute) -> Option<String> {
+        expected_message(&attr).map(|msg| msg.value())
+    }
+
+    #[test]
+    fn expected_message_of_should_panic() {
+        assert_eq!(expected(syn::parse_quote!(#[should_panic])), None);
+        assert_eq!(
+            expected(syn::parse_quote!(#[should_panic(expected = "overflow")])),
+            Some("overflow".to_string())
+        );
+        assert_eq!(
+            expected(syn::parse_quote!(#[should_panic = "overflow"])),
+            Some("overflow".to_string())
+        );
+    }
+
+    #[test]
+    fn expand_keeps_a_single_body() {
+        let function = syn::parse_quote! {
+            #[should_panic]
+            fn parse_large_file() {
+                let marker = 42;
+            }
+        };
+        let expanded = expand(&Settings::default(), function).to_string();
+        assert_eq!(expanded.matches("let marker = 42").count(), 1);
+        assert_eq!(expanded.matches("# [should_panic]").count(), 1);
+        assert!(expanded.contains("fn parse_large_file ()"));
+        assert!(expanded.contains("fn __coppers_parse_large_file ()"));
+    }
+}
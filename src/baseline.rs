@@ -0,0 +1,375 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The baseline records the accepted energy per iteration of every test, and is meant to be
+// checked into the repository. When a test deviates from its accepted energy by more than its
+// tolerance, or when a test is not part of the baseline yet, the runner writes a pending file
+// with the new energy of that test. Running `cargo coppers accept` moves the pending energy into
+// the baseline, so the change shows up in the diff of the baseline.
+
+use crate::test_runner::Environment;
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, write};
+use std::path::{Path, PathBuf};
+
+pub(crate) const BASELINE_FILE_NAME: &str = "coppers-baseline.json";
+
+// Directory within the target directory where the pending files are written to
+const PENDING_DIRECTORY_NAME: &str = "coppers_baseline";
+
+const PENDING_EXTENSION: &str = "pending";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, PartialEq)]
+pub(crate) struct Baseline {
+    // Sorted by key, so the baseline file has a stable order. The key of a test is the name of
+    // its test binary and its name, like `integration::tests::parse_large_file`, because tests
+    // in different test binaries can have the same name.
+    pub(crate) tests: BTreeMap<String, BaselineEntry>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct BaselineEntry {
+    // Accepted energy per iteration
+    pub(crate) uj: u128,
+    // Maximum deviation from the accepted energy in percent, which overrides the default
+    // tolerance of the configuration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tolerance: Option<f64>,
+    // The environment in which the energy is accepted. Baselines from before the environment was
+    // recorded are compared with every environment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) environment: Option<Environment>,
+}
+
+// The energy of a test that deviates from the baseline, and is waiting to be accepted
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Pending {
+    pub(crate) name: String,
+    // The test binary that the test is part of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) binary: Option<String>,
+    // Energy per iteration in the run that wrote this file
+    pub(crate) uj: u128,
+    // Energy per iteration in the baseline, if the test is part of it
+    pub(crate) accepted_uj: Option<u128>,
+    pub(crate) tolerance: f64,
+    // The environment of the run that wrote the pending file, which is accepted together with
+    // the energy. The results of a run record their environment already, so it is left out there.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) environment: Option<Environment>,
+}
+
+impl Baseline {
+    // A missing baseline file is the same as an empty baseline
+    pub(crate) fn load(root: &Path) -> Result<Baseline, String> {
+        let path = root.join(BASELINE_FILE_NAME);
+        match read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Invalid {}: {e}", path.display())),
+            Err(_) => Ok(Baseline::default()),
+        }
+    }
+
+    pub(crate) fn save(&self, root: &Path) -> Result<(), String> {
+        let path = root.join(BASELINE_FILE_NAME);
+        let mut contents = serde_json::to_string_pretty(self).unwrap();
+        contents.push('\n');
+        write(&path, contents).map_err(|e| format!("Could not write {}: {e}", path.display()))
+    }
+
+    // Compare the energy per iteration of a test with the baseline. Returns the pending energy
+    // if the test is not part of the baseline, or deviates too much from it.
+    pub(crate) fn compare(
+        &self,
+        binary: Option<&str>,
+        name: &str,
+        uj: u128,
+        default_tolerance: f64,
+    ) -> Option<Pending> {
+        let entry = self.entry(binary, name);
+        let tolerance = entry
+            .and_then(|entry| entry.tolerance)
+            .unwrap_or(default_tolerance);
+        let pending = Pending {
+            name: name.to_string(),
+            binary: binary.map(str::to_string),
+            uj,
+            accepted_uj: entry.map(|entry| entry.uj),
+            tolerance,
+            environment: None,
+        };
+        let Some(entry) = entry else {
+            return Some(pending);
+        };
+        let deviation = if entry.uj == 0 {
+            if uj == 0 {
+                0.0
+            } else {
+                f64::INFINITY
+            }
+        } else {
+            (uj as f64 - entry.uj as f64).abs() / entry.uj as f64 * 100.0
+        };
+        (deviation > tolerance).then_some(pending)
+    }
+
+    // Energy that is accepted in an environment that is not comparable with the current one,
+    // like on other hardware or with another sensor
+    pub(crate) fn recorded_elsewhere(
+        &self,
+        binary: Option<&str>,
+        name: &str,
+        environment: &Environment,
+    ) -> bool {
+        self.entry(binary, name)
+            .and_then(|entry| entry.environment.as_ref())
+            .is_some_and(|recorded| !recorded.comparable_with(environment))
+    }
+
+    // Baselines from before the test binary was part of the key only know the name of a test
+    fn entry(&self, binary: Option<&str>, name: &str) -> Option<&BaselineEntry> {
+        self.tests
+            .get(&key(binary, name))
+            .or_else(|| self.tests.get(name))
+    }
+
+    // Replace the accepted energy of a test by its pending energy, and keep its tolerance
+    pub(crate) fn accept(&mut self, pending: &Pending) {
+        let tolerance = self
+            .entry(pending.binary.as_deref(), &pending.name)
+            .and_then(|entry| entry.tolerance);
+        self.tests.insert(
+            pending.key(),
+            BaselineEntry {
+                uj: pending.uj,
+                tolerance,
+                environment: pending.environment.clone(),
+            },
+        );
+    }
+}
+
+impl Pending {
+    // The key of the test in the baseline
+    pub(crate) fn key(&self) -> String {
+        key(self.binary.as_deref(), &self.name)
+    }
+
+    pub(crate) fn write(&self, directory: &Path) -> Result<(), String> {
+        create_dir_all(directory).map_err(|e| e.to_string())?;
+        let path = pending_path(directory, &self.key());
+        write(&path, serde_json::to_string_pretty(self).unwrap())
+            .map_err(|e| format!("Could not write {}: {e}", path.display()))
+    }
+
+    // The pending files of all tests, sorted by the key of the test
+    pub(crate) fn load_all(directory: &Path) -> Result<Vec<(PathBuf, Pending)>, String> {
+        let Ok(entries) = read_dir(directory) else {
+            return Ok(Vec::new());
+        };
+        let mut pending = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(PENDING_EXTENSION) {
+                continue;
+            }
+            let contents = read_to_string(&path).map_err(|e| e.to_string())?;
+            let test: Pending = serde_json::from_str(&contents)
+                .map_err(|e| format!("Invalid {}: {e}", path.display()))?;
+            pending.push((path, test));
+        }
+        pending.sort_by_key(|(_, test)| test.key());
+        Ok(pending)
+    }
+
+    // Remove the pending file of a test, which is outdated once the test matches the baseline
+    pub(crate) fn remove(directory: &Path, binary: Option<&str>, name: &str) {
+        let _ = remove_file(pending_path(directory, &key(binary, name)));
+    }
+}
+
+// The directory of the pending files, which are not checked into the repository
+pub(crate) fn pending_directory(target_directory: &Path) -> PathBuf {
+    target_directory.join(PENDING_DIRECTORY_NAME)
+}
+
+// Tests of which the test binary is unknown are only known by their name
+fn key(binary: Option<&str>, name: &str) -> String {
+    match binary {
+        Some(binary) => format!("{binary}::{name}"),
+        None => name.to_string(),
+    }
+}
+
+// Test names contain `::`, which is not allowed in file names on every platform
+fn pending_path(directory: &Path, key: &str) -> PathBuf {
+    directory.join(format!("{}.{PENDING_EXTENSION}", key.replace("::", "-")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn baseline(uj: u128, tolerance: Option<f64>) -> Baseline {
+        Baseline {
+            tests: BTreeMap::from([(
+                "integration::tests::a".to_string(),
+                BaselineEntry {
+                    uj,
+                    tolerance,
+                    environment: None,
+                },
+            )]),
+        }
+    }
+
+    #[test]
+    fn compare_within_tolerance() {
+        let baseline = baseline(1000, None);
+        assert_eq!(
+            baseline.compare(Some("integration"), "tests::a", 1100, 10.0),
+            None
+        );
+        assert_eq!(
+            baseline.compare(Some("integration"), "tests::a", 900, 10.0),
+            None
+        );
+    }
+
+    #[test]
+    fn compare_outside_tolerance() {
+        let baseline = baseline(1000, None);
+        let pending = baseline
+            .compare(Some("integration"), "tests::a", 1101, 10.0)
+            .unwrap();
+        assert_eq!(pending.accepted_uj, Some(1000));
+        assert_eq!(pending.uj, 1101);
+        // Improvements need to be accepted as well, so the baseline stays up to date
+        assert!(baseline
+            .compare(Some("integration"), "tests::a", 899, 10.0)
+            .is_some());
+    }
+
+    #[test]
+    fn compare_with_tolerance_of_test() {
+        let baseline = baseline(1000, Some(50.0));
+        assert_eq!(
+            baseline.compare(Some("integration"), "tests::a", 1400, 10.0),
+            None
+        );
+    }
+
+    #[test]
+    fn compare_new_test() {
+        let pending = Baseline::default()
+            .compare(Some("integration"), "tests::b", 10, 10.0)
+            .unwrap();
+        assert_eq!(pending.accepted_uj, None);
+    }
+
+    #[test]
+    fn accept_keeps_tolerance() {
+        let mut baseline = baseline(1000, Some(50.0));
+        let pending = baseline
+            .compare(Some("integration"), "tests::a", 2000, 10.0)
+            .unwrap();
+        baseline.accept(&pending);
+        assert_eq!(
+            baseline.tests["integration::tests::a"],
+            BaselineEntry {
+                uj: 2000,
+                tolerance: Some(50.0),
+                environment: None,
+            }
+        );
+    }
+
+    #[test]
+    fn accept_records_environment() {
+        let mut baseline = Baseline::default();
+        let here = Environment {
+            hostname: "here".to_string(),
+            ..Environment::default()
+        };
+        let pending = Pending {
+            environment: Some(here.clone()),
+            ..baseline
+                .compare(Some("integration"), "tests::a", 1000, 10.0)
+                .unwrap()
+        };
+        baseline.accept(&pending);
+        assert!(!baseline.recorded_elsewhere(Some("integration"), "tests::a", &here));
+        let elsewhere = Environment {
+            sensor: "time".to_string(),
+            ..here.clone()
+        };
+        assert!(baseline.recorded_elsewhere(Some("integration"), "tests::a", &elsewhere));
+        // Tests that are not part of the baseline are not recorded anywhere
+        assert!(!baseline.recorded_elsewhere(Some("integration"), "tests::b", &elsewhere));
+    }
+
+    #[test]
+    fn compare_baseline_from_other_host() {
+        let mut baseline = Baseline::default();
+        let here = Environment {
+            hostname: "developer".to_string(),
+            kernel: Some("6.1.0".to_string()),
+            ..Environment::default()
+        };
+        let pending = Pending {
+            environment: Some(here.clone()),
+            ..baseline
+                .compare(Some("integration"), "tests::a", 1000, 10.0)
+                .unwrap()
+        };
+        baseline.accept(&pending);
+        let ci = Environment {
+            hostname: "ci-runner".to_string(),
+            kernel: Some("6.8.0".to_string()),
+            ..here
+        };
+        assert!(!baseline.recorded_elsewhere(Some("integration"), "tests::a", &ci));
+        assert!(baseline
+            .compare(Some("integration"), "tests::a", 2000, 10.0)
+            .is_some());
+    }
+
+    #[test]
+    fn compare_same_name_in_other_binary() {
+        let baseline = baseline(1000, None);
+        let pending = baseline
+            .compare(Some("other"), "tests::a", 1000, 10.0)
+            .unwrap();
+        assert_eq!(pending.accepted_uj, None);
+        assert_eq!(pending.key(), "other::tests::a");
+    }
+
+    #[test]
+    fn compare_with_baseline_without_binaries() {
+        let baseline = Baseline {
+            tests: BTreeMap::from([(
+                "tests::a".to_string(),
+                BaselineEntry {
+                    uj: 1000,
+                    tolerance: None,
+                    environment: None,
+                },
+            )]),
+        };
+        assert_eq!(
+            baseline.compare(Some("integration"), "tests::a", 1000, 10.0),
+            None
+        );
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Implementation of `cargo coppers`. Cargo runs the `cargo-coppers` binary for it, with
+// `coppers` as the first argument.
+
+use crate::baseline::{pending_directory, Baseline, Pending, BASELINE_FILE_NAME};
+use crate::config::{
+    Config, CsvRows, OutputFormat, StoreKind, ARGUMENTS_SEPARATOR, ARGUMENTS_VARIABLE,
+};
+use crate::test_runner::{
+    export_csv, import as import_results, open_store, own_invocation_id, results_directory,
+    write_junit, JsonResult, INVOCATION_VARIABLE, RESULT_FILE_VARIABLE,
+};
+use std::env;
+use std::fs::{read_to_string, remove_file};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+const USAGE: &str = "Usage: cargo coppers <command>
+
+Commands:
+    test [options] [-- runner options]    Run all test binaries and merge their results
+    accept [tests...]                     Accept the pending energy of the tests into the baseline
+    reject [tests...]                     Discard the pending energy of the tests
+    import [runner options]               Import the JSON results into the SQLite store
+    export [runner options]               Export the results of all runs to a CSV file
+
+The options of `test` are passed to `cargo test`, and the runner options to every test binary
+that uses the Coppers runner.
+Without any tests, `accept` and `reject` apply to all tests with pending energy.";
+
+pub fn main() {
+    let mut arguments = env::args().skip(1).peekable();
+    if arguments.peek().map(String::as_str) == Some("coppers") {
+        arguments.next();
+    }
+    let command = arguments.next();
+    let tests: Vec<String> = arguments.collect();
+    let result = match command.as_deref() {
+        Some("test") => test(&tests),
+        Some("accept") => accept(&tests),
+        Some("reject") => reject(&tests),
+        Some("import") => import(&tests),
+        Some("export") => export(&tests),
+        Some("help" | "--help" | "-h") => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+// A test binary that is built by cargo
+#[derive(Debug, PartialEq)]
+struct TestBinary {
+    name: String,
+    executable: PathBuf,
+    // The directory of the package the binary is part of, from which cargo runs it as well
+    directory: PathBuf,
+}
+
+// Build all test binaries, run them one after another, and write their merged results as a
+// single run. Exits with the same code as `cargo test` when a test binary fails.
+fn test(arguments: &[String]) -> Result<(), String> {
+    let (cargo_arguments, runner_arguments) =
+        match arguments.iter().position(|argument| argument == "--") {
+            Some(separator) => (&arguments[..separator], &arguments[separator + 1..]),
+            None => (arguments, &[][..]),
+        };
+
+    let root = crate_root()?;
+    let config = Config::load_from(&root, runner_arguments.iter().cloned())?;
+    // The output of the test binaries is the only output on the standard output, unless it is for
+    // people to follow along
+    let status = |message: String| match config.format {
+        OutputFormat::Pretty | OutputFormat::Terse => println!("{message}"),
+        OutputFormat::Tap | OutputFormat::Quiet | OutputFormat::Json => eprintln!("{message}"),
+    };
+
+    let build = Command::new(cargo())
+        .args([
+            "test",
+            "--no-run",
+            "--message-format=json-render-diagnostics",
+        ])
+        .args(cargo_arguments)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Could not run cargo: {e}"))?;
+    if !build.status.success() {
+        return Err("Could not build the test binaries".to_string());
+    }
+
+    // The test binaries are grouped under the same invocation, even if the ID of this process
+    // cannot be determined by them
+    let invocation_id = own_invocation_id();
+    let mut success = true;
+    let mut results = Vec::new();
+    let test_binaries = test_binaries(&String::from_utf8_lossy(&build.stdout));
+    for (i, binary) in test_binaries.iter().enumerate() {
+        status(format!(
+            "     Running {} ({})",
+            binary.name,
+            binary.executable.display()
+        ));
+        let result_file = env::temp_dir().join(format!("coppers-{}-{i}.json", process::id()));
+        // The runner options are not passed on the command line, which libtest would reject
+        let status = Command::new(&binary.executable)
+            .env(
+                ARGUMENTS_VARIABLE,
+                runner_arguments.join(&ARGUMENTS_SEPARATOR.to_string()),
+            )
+            .current_dir(&binary.directory)
+            .env("CARGO_MANIFEST_DIR", &binary.directory)
+            .env(RESULT_FILE_VARIABLE, &result_file)
+            .env(INVOCATION_VARIABLE, &invocation_id)
+            .status()
+            .map_err(|e| format!("Could not run {}: {e}", binary.executable.display()))?;
+        success &= status.success();
+
+        // Binaries that do not use the Coppers runner do not write a result
+        if let Ok(contents) = read_to_string(&result_file) {
+            let _ = remove_file(&result_file);
+            let result = JsonResult::from_json(&contents)
+                .map_err(|e| format!("Invalid result of {}: {e}", binary.name))?;
+            results.push(result);
+        }
+    }
+
+    let binaries = results.len();
+    if let Some(merged) = JsonResult::merge(results) {
+        // The merged results end up next to the results of the test binaries themselves
+        let directory = results_directory(
+            &config,
+            &root,
+            test_binaries
+                .first()
+                .map(|binary| binary.executable.as_path()),
+        );
+        let mut store = open_store(config.store, &directory)?;
+        store.save(&merged)?;
+        status(format!("merged the results of {binaries} test binaries"));
+        if let Some(rows) = config.csv {
+            let path = export_csv(store.as_ref(), rows, &directory)?;
+            status(format!("exported the results to {}", path.display()));
+        }
+        if let Some(path) = &config.junit {
+            write_junit(&merged, &root.join(path))?;
+        }
+
+        #[cfg(feature = "visualization")]
+        crate::test_runner::visualize(store.as_ref(), &merged, &directory)?;
+    }
+    if !success {
+        process::exit(101);
+    }
+    Ok(())
+}
+
+// The test binaries in the JSON messages of `cargo test --no-run`
+fn test_binaries(messages: &str) -> Vec<TestBinary> {
+    messages
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|message| {
+            message["reason"] == "compiler-artifact" && message["profile"]["test"] == true
+        })
+        .filter_map(|message| {
+            Some(TestBinary {
+                name: message["target"]["name"].as_str()?.to_string(),
+                executable: PathBuf::from(message["executable"].as_str()?),
+                directory: Path::new(message["manifest_path"].as_str()?)
+                    .parent()?
+                    .to_path_buf(),
+            })
+        })
+        .collect()
+}
+
+fn accept(tests: &[String]) -> Result<(), String> {
+    let root = crate_root()?;
+    let mut baseline = Baseline::load(&root)?;
+    let pending = selected(
+        Pending::load_all(&pending_directory(&cargo_target_directory(&root)?))?,
+        tests,
+    );
+    // The baseline file is left alone, so accepting twice does not change it
+    if pending.is_empty() {
+        println!("no tests with pending energy to accept");
+        return Ok(());
+    }
+    for (_, test) in &pending {
+        baseline.accept(test);
+    }
+    baseline.save(&root)?;
+    for (path, test) in &pending {
+        remove_file(path).map_err(|e| e.to_string())?;
+        println!("accepted {}: {} μJ per iteration", test.key(), test.uj);
+    }
+    println!("{} tests accepted into {BASELINE_FILE_NAME}", pending.len());
+    Ok(())
+}
+
+fn reject(tests: &[String]) -> Result<(), String> {
+    let root = crate_root()?;
+    let pending = selected(
+        Pending::load_all(&pending_directory(&cargo_target_directory(&root)?))?,
+        tests,
+    );
+    for (path, test) in &pending {
+        remove_file(path).map_err(|e| e.to_string())?;
+        println!("rejected {}", test.key());
+    }
+    println!("{} tests rejected", pending.len());
+    Ok(())
+}
+
+// Only keep the pending tests with one of the given names, or all of them if no names are given.
+// A test is either named by its name, or by the name of its test binary and its name.
+fn selected(pending: Vec<(PathBuf, Pending)>, tests: &[String]) -> Vec<(PathBuf, Pending)> {
+    pending
+        .into_iter()
+        .filter(|(_, test)| {
+            tests.is_empty() || tests.contains(&test.name) || tests.contains(&test.key())
+        })
+        .collect()
+}
+
+// Add the results that are stored as JSON files to the SQLite store, which is configured with
+// `store = "sqlite"` or `--store sqlite`
+fn import(arguments: &[String]) -> Result<(), String> {
+    let root = crate_root()?;
+    let config = Config::load_from(&root, arguments.iter().cloned())?;
+    if config.store != StoreKind::Sqlite {
+        return Err("The results are only imported into the SQLite store, which is used with `store = \"sqlite\"` in coppers.toml or `--store sqlite`".to_string());
+    }
+    let directory = results_directory(&config, &root, None);
+    let mut store = open_store(config.store, &directory)?;
+    let imported = import_results(&directory, store.as_mut())?;
+    println!("imported {imported} runs from {}", directory.display());
+    Ok(())
+}
+
+// Export the results of all runs to a CSV file, with a row per test unless `--csv iterations`
+// is given
+fn export(arguments: &[String]) -> Result<(), String> {
+    let root = crate_root()?;
+    let config = Config::load_from(&root, arguments.iter().cloned())?;
+    let directory = results_directory(&config, &root, None);
+    let store = open_store(config.store, &directory)?;
+    let path = export_csv(
+        store.as_ref(),
+        config.csv.unwrap_or(CsvRows::Tests),
+        &directory,
+    )?;
+    println!(
+        "exported the results in {} to {}",
+        directory.display(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn cargo() -> String {
+    env::var("CARGO").unwrap_or_else(|_| "cargo".to_string())
+}
+
+// The test binaries write their pending files to the target directory that they are built in.
+// Cargo knows where that is, including when it is moved with `CARGO_TARGET_DIR` or the
+// `build.target-dir` setting.
+fn cargo_target_directory(root: &Path) -> Result<PathBuf, String> {
+    let metadata = Command::new(cargo())
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .current_dir(root)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Could not run cargo: {e}"))?;
+    if !metadata.status.success() {
+        return Err("Could not read the metadata of the crate".to_string());
+    }
+    target_directory_of(&String::from_utf8_lossy(&metadata.stdout))
+        .ok_or_else(|| "The metadata of the crate has no target directory".to_string())
+}
+
+// The target directory in the JSON output of `cargo metadata`
+fn target_directory_of(metadata: &str) -> Option<PathBuf> {
+    let metadata: serde_json::Value = serde_json::from_str(metadata).ok()?;
+    metadata["target_directory"].as_str().map(PathBuf::from)
+}
+
+// The closest directory with a `Cargo.toml`, starting from the current directory
+fn crate_root() -> Result<PathBuf, String> {
+    let current = env::current_dir().map_err(|e| e.to_string())?;
+    current
+        .ancestors()
+        .find(|directory| directory.join("Cargo.toml").is_file())
+        .map(PathBuf::from)
+        .ok_or_else(|| "Could not find a Cargo.toml in this directory or its parents".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binaries_from_messages() {
+        let messages = r#"{"reason":"compiler-artifact","target":{"name":"coppers"},"profile":{"test":false},"executable":null,"manifest_path":"/crate/Cargo.toml"}
+{"reason":"compiler-artifact","target":{"name":"integration"},"profile":{"test":true},"executable":"/crate/target/debug/deps/integration-0123456789abcdef","manifest_path":"/crate/Cargo.toml"}
+{"reason":"build-finished","success":true}"#;
+        assert_eq!(
+            test_binaries(messages),
+            [TestBinary {
+                name: "integration".to_string(),
+                executable: PathBuf::from("/crate/target/debug/deps/integration-0123456789abcdef"),
+                directory: PathBuf::from("/crate"),
+            }]
+        );
+    }
+
+    #[test]
+    fn target_directory_from_metadata() {
+        let metadata = r#"{"packages":[],"target_directory":"/elsewhere/target","version":1}"#;
+        assert_eq!(
+            target_directory_of(metadata),
+            Some(PathBuf::from("/elsewhere/target"))
+        );
+        assert_eq!(target_directory_of("{}"), None);
+    }
+}
@@ -0,0 +1,628 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Name of the optional configuration file in the root of the crate that is tested
+pub(crate) const CONFIG_FILE_NAME: &str = "coppers.toml";
+
+// `cargo coppers test` passes the runner arguments in this variable instead of on the command
+// line, because the test binaries that use libtest reject the arguments they do not know
+pub(crate) const ARGUMENTS_VARIABLE: &str = "COPPERS_ARGS";
+
+// Separates the arguments in `COPPERS_ARGS`
+pub(crate) const ARGUMENTS_SEPARATOR: char = '\u{1f}';
+
+// Configuration of the runner. The defaults can be overridden by a `coppers.toml` file in
+// the root of the crate, which can in turn be overridden by command line arguments that are
+// passed with `cargo test -- <arguments>`.
+#[derive(serde::Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) outliers: OutlierConfig,
+    // Default amount of seconds a test may run, including all its iterations
+    pub(crate) timeout: Option<u64>,
+    // Settings of individual tests, by the name of the test
+    pub(crate) tests: HashMap<String, TestConfig>,
+    pub(crate) isolation: IsolationConfig,
+    // Amount of tests that run at the same time. Tests run one after the other by default.
+    pub(crate) test_threads: Option<usize>,
+    pub(crate) regression: RegressionConfig,
+    pub(crate) baseline: BaselineConfig,
+    // Whether only the named regions of the tests are measured, instead of the whole tests
+    pub(crate) regions_only: bool,
+    // Whether the commit, branch and uncommitted changes of the repository are left out of the
+    // results
+    pub(crate) omit_git: bool,
+    // Directory the results are written to, relative to the root of the crate. By default, the
+    // results are written to `coppers_results` in the target directory.
+    pub(crate) results_dir: Option<PathBuf>,
+    // How the results are stored in the results directory
+    pub(crate) store: StoreKind,
+    // Whether all runs are exported to a CSV file after every run, with a row per test or per
+    // iteration
+    pub(crate) csv: Option<CsvRows>,
+    // File the results of every run are written to in the JUnit XML format, relative to the root
+    // of the crate
+    pub(crate) junit: Option<PathBuf>,
+    // How the progress and the results of a run are written to the console
+    pub(crate) format: OutputFormat,
+    // Set when this process is started by the runner to run a single test in isolation
+    #[serde(skip)]
+    pub(crate) child_test: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TestConfig {
+    pub(crate) timeout: Option<u64>,
+    // Amount of energy the test may consume per iteration, in μJ
+    #[serde(deserialize_with = "deserialize_uj")]
+    pub(crate) budget_uj: Option<u128>,
+    pub(crate) regions_only: Option<bool>,
+}
+
+// TOML integers are 64 bits at most, while energy is a `u128` everywhere else
+fn deserialize_uj<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u128>, D::Error> {
+    let uj: Option<u64> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(uj.map(u128::from))
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct IsolationConfig {
+    pub(crate) mode: IsolationMode,
+    // Whether the energy of starting and stopping the process of a test counts towards the
+    // energy of that test
+    pub(crate) include_startup: bool,
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum IsolationMode {
+    // Run every test on its own thread within the process of the runner
+    #[default]
+    Thread,
+    // Run every test in its own process
+    Process,
+}
+
+impl std::str::FromStr for IsolationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "thread" => Ok(IsolationMode::Thread),
+            "process" => Ok(IsolationMode::Process),
+            _ => Err(format!(
+                "Unknown isolation mode `{s}`, expected either `thread` or `process`"
+            )),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StoreKind {
+    // A JSON file per run
+    #[default]
+    Json,
+    // A single SQLite database, which requires the `sqlite` feature
+    Sqlite,
+}
+
+impl std::str::FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(StoreKind::Json),
+            "sqlite" => Ok(StoreKind::Sqlite),
+            _ => Err(format!(
+                "Unknown store `{s}`, expected either `json` or `sqlite`"
+            )),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OutputFormat {
+    // A line with the energy of every test
+    #[default]
+    Pretty,
+    // A character for every test
+    Terse,
+    // The Test Anything Protocol, version 13, with the energy of every test in its diagnostics
+    Tap,
+    // Only the failures, regressions and deviations from the baseline
+    Quiet,
+    // The event stream of `--format json` of libtest, with the energy of every test
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(OutputFormat::Pretty),
+            "terse" => Ok(OutputFormat::Terse),
+            "tap" => Ok(OutputFormat::Tap),
+            "quiet" => Ok(OutputFormat::Quiet),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "Unknown format `{s}`, expected one of `pretty`, `terse`, `tap`, `quiet` or `json`"
+            )),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CsvRows {
+    // A row per test in a run
+    Tests,
+    // A row per iteration of a test in a run
+    Iterations,
+}
+
+impl std::str::FromStr for CsvRows {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tests" => Ok(CsvRows::Tests),
+            "iterations" => Ok(CsvRows::Iterations),
+            _ => Err(format!(
+                "Unknown CSV rows `{s}`, expected either `tests` or `iterations`"
+            )),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OutlierConfig {
+    pub(crate) method: OutlierMethod,
+    // Whether the iterations that are flagged as outlier are left out of the aggregated
+    // energy and time of a test. They are always kept in the per-iteration results.
+    pub(crate) exclude: bool,
+    // Multiplier of the interquartile range that is used for the Tukey fences
+    pub(crate) tukey_k: f64,
+    // Maximum modified z-score of an iteration before it is flagged by the MAD method
+    pub(crate) mad_threshold: f64,
+}
+
+impl Default for OutlierConfig {
+    fn default() -> Self {
+        OutlierConfig {
+            method: OutlierMethod::Tukey,
+            exclude: false,
+            tukey_k: 1.5,
+            mad_threshold: 3.5,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OutlierMethod {
+    None,
+    Tukey,
+    Mad,
+}
+
+impl std::str::FromStr for OutlierMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(OutlierMethod::None),
+            "tukey" => Ok(OutlierMethod::Tukey),
+            "mad" => Ok(OutlierMethod::Mad),
+            _ => Err(format!(
+                "Unknown outlier method `{s}`, expected one of `none`, `tukey` or `mad`"
+            )),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RegressionConfig {
+    // Whether the run fails when a test consumes more energy than in the previous runs
+    pub(crate) enabled: bool,
+    // Amount of previous runs on the same host to compare with
+    pub(crate) history: usize,
+    pub(crate) method: RegressionMethod,
+    // Maximum p-value of an increase before it is significant with the Mann-Whitney U test
+    pub(crate) significance: f64,
+    // Minimum increase of the energy per iteration of a test, in percent
+    pub(crate) threshold: f64,
+}
+
+impl Default for RegressionConfig {
+    fn default() -> Self {
+        RegressionConfig {
+            enabled: false,
+            history: 5,
+            method: RegressionMethod::MannWhitney,
+            significance: 0.05,
+            threshold: 5.0,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RegressionMethod {
+    // The iterations of a test are significantly larger according to a Mann-Whitney U test
+    MannWhitney,
+    // The confidence intervals of the mean energy of the iterations do not overlap
+    Threshold,
+}
+
+impl std::str::FromStr for RegressionMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mann-whitney" => Ok(RegressionMethod::MannWhitney),
+            "threshold" => Ok(RegressionMethod::Threshold),
+            _ => Err(format!(
+                "Unknown regression method `{s}`, expected either `mann-whitney` or `threshold`"
+            )),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BaselineConfig {
+    // Whether the tests are compared with the baseline that is checked into the repository
+    pub(crate) enabled: bool,
+    // Maximum deviation from the accepted energy of a test in percent, unless the baseline sets
+    // a tolerance for that test
+    pub(crate) tolerance: f64,
+}
+
+impl Default for BaselineConfig {
+    fn default() -> Self {
+        BaselineConfig {
+            enabled: false,
+            tolerance: 10.0,
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn load() -> Result<Config, String> {
+        let passed = env::var(ARGUMENTS_VARIABLE).unwrap_or_default();
+        let passed = passed
+            .split(ARGUMENTS_SEPARATOR)
+            .filter(|argument| !argument.is_empty())
+            .map(str::to_string);
+        // The arguments on the command line come last, so they take precedence
+        Config::load_from(&crate_root(), passed.chain(env::args().skip(1)))
+    }
+
+    // The configuration of the crate in `root`, with the runner arguments applied to it
+    pub(crate) fn load_from(
+        root: &Path,
+        arguments: impl Iterator<Item = String>,
+    ) -> Result<Config, String> {
+        let mut config = match read_to_string(root.join(CONFIG_FILE_NAME)) {
+            Ok(contents) => Config::from_toml(&contents)?,
+            Err(_) => Config::default(),
+        };
+        config.apply_arguments(arguments)?;
+        Ok(config)
+    }
+
+    // The timeout of a specific test takes precedence over the default timeout
+    pub(crate) fn timeout_of(&self, test_name: &str) -> Option<Duration> {
+        self.tests
+            .get(test_name)
+            .and_then(|test| test.timeout)
+            .or(self.timeout)
+            .map(Duration::from_secs)
+    }
+
+    // The budget in the configuration takes precedence over the budget that is set in the test
+    pub(crate) fn budget_of(&self, test_name: &str) -> Option<u128> {
+        self.tests.get(test_name).and_then(|test| test.budget_uj)
+    }
+
+    pub(crate) fn regions_only_of(&self, test_name: &str) -> bool {
+        self.tests
+            .get(test_name)
+            .and_then(|test| test.regions_only)
+            .unwrap_or(self.regions_only)
+    }
+
+    pub(crate) fn test_threads(&self) -> usize {
+        self.test_threads.unwrap_or(1).max(1)
+    }
+
+    fn from_toml(contents: &str) -> Result<Config, String> {
+        toml::from_str(contents).map_err(|e| format!("Invalid {CONFIG_FILE_NAME}: {e}"))
+    }
+
+    // Arguments that are not recognized are ignored, so the filters and flags that are
+    // meant for libtest do not cause an error.
+    fn apply_arguments(
+        &mut self,
+        mut arguments: impl Iterator<Item = String>,
+    ) -> Result<(), String> {
+        while let Some(argument) = arguments.next() {
+            let (flag, inline_value) = match argument.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (argument, None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| arguments.next())
+                    .ok_or(format!("Missing value for argument `{flag}`"))
+            };
+            match flag.as_str() {
+                "--outliers" => self.outliers.method = value()?.parse()?,
+                "--exclude-outliers" => self.outliers.exclude = true,
+                "--timeout" => self.timeout = Some(parse_value(&flag, value()?)?),
+                "--isolation" => self.isolation.mode = value()?.parse()?,
+                "--include-startup" => self.isolation.include_startup = true,
+                "--test-threads" => self.test_threads = Some(parse_value(&flag, value()?)?),
+                "--regression" => self.regression.enabled = true,
+                "--regression-history" => {
+                    self.regression.enabled = true;
+                    self.regression.history = parse_value(&flag, value()?)?;
+                }
+                "--regression-method" => {
+                    self.regression.enabled = true;
+                    self.regression.method = value()?.parse()?;
+                }
+                "--baseline" => self.baseline.enabled = true,
+                "--regions-only" => self.regions_only = true,
+                "--omit-git" => self.omit_git = true,
+                "--results-dir" => self.results_dir = Some(PathBuf::from(value()?)),
+                "--store" => self.store = value()?.parse()?,
+                "--csv" => self.csv = Some(value()?.parse()?),
+                "--junit" => self.junit = Some(PathBuf::from(value()?)),
+                "--format" => self.format = value()?.parse()?,
+                "--coppers-run-test" => self.child_test = Some(value()?),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value `{value}` for argument `{flag}`"))
+}
+
+// The root of the crate that is being tested, which Cargo exposes while running the tests. Fall
+// back to the current directory otherwise.
+pub(crate) fn crate_root() -> PathBuf {
+    env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(arguments: &[&str]) -> impl Iterator<Item = String> {
+        arguments
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn config_from_empty_toml_is_default() {
+        assert_eq!(Config::from_toml("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn config_from_toml_outliers() {
+        let config = Config::from_toml(
+            r#"
+            [outliers]
+            method = "mad"
+            exclude = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.outliers.method, OutlierMethod::Mad);
+        assert!(config.outliers.exclude);
+        assert_eq!(config.outliers.mad_threshold, 3.5);
+    }
+
+    #[test]
+    fn config_timeout_per_test() {
+        let config = Config::from_toml(
+            r#"
+            timeout = 60
+
+            [tests."tests::slow_test"]
+            timeout = 300
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.timeout_of("tests::slow_test"),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            config.timeout_of("tests::other_test"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(Config::default().timeout_of("tests::other_test"), None);
+    }
+
+    #[test]
+    fn config_budget_per_test() {
+        let config = Config::from_toml(
+            r#"
+            [tests."tests::parse_large_file"]
+            budget_uj = 5000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.budget_of("tests::parse_large_file"), Some(5000));
+        assert_eq!(config.budget_of("tests::other_test"), None);
+    }
+
+    #[test]
+    fn config_regions_only_per_test() {
+        let config = Config::from_toml(
+            r#"
+            regions_only = true
+
+            [tests."tests::whole_test"]
+            regions_only = false
+            "#,
+        )
+        .unwrap();
+        assert!(!config.regions_only_of("tests::whole_test"));
+        assert!(config.regions_only_of("tests::other_test"));
+        assert!(!Config::default().regions_only_of("tests::other_test"));
+    }
+
+    #[test]
+    fn config_from_toml_isolation() {
+        let config = Config::from_toml(
+            r#"
+            [isolation]
+            mode = "process"
+            include_startup = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.isolation.mode, IsolationMode::Process);
+        assert!(config.isolation.include_startup);
+        assert_eq!(Config::default().isolation.mode, IsolationMode::Thread);
+    }
+
+    #[test]
+    fn config_from_toml_regression() {
+        let config = Config::from_toml(
+            r#"
+            [regression]
+            enabled = true
+            method = "threshold"
+            history = 10
+            "#,
+        )
+        .unwrap();
+        assert!(config.regression.enabled);
+        assert_eq!(config.regression.method, RegressionMethod::Threshold);
+        assert_eq!(config.regression.history, 10);
+        assert_eq!(config.regression.threshold, 5.0);
+        assert!(!Config::default().regression.enabled);
+    }
+
+    #[test]
+    fn config_from_toml_baseline() {
+        let config = Config::from_toml(
+            r#"
+            [baseline]
+            enabled = true
+            tolerance = 20.0
+            "#,
+        )
+        .unwrap();
+        assert!(config.baseline.enabled);
+        assert_eq!(config.baseline.tolerance, 20.0);
+        assert_eq!(Config::default().baseline.tolerance, 10.0);
+    }
+
+    #[test]
+    fn config_from_toml_unknown_field() {
+        assert!(Config::from_toml("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn config_arguments_override() {
+        let mut config = Config::default();
+        config
+            .apply_arguments(arguments(&[
+                "--outliers",
+                "none",
+                "--exclude-outliers",
+                "--timeout",
+                "10",
+                "--isolation=process",
+                "--coppers-run-test",
+                "tests::some_test",
+                "--test-threads",
+                "4",
+                "--regression-history=3",
+                "--results-dir",
+                "/tmp/results",
+                "--csv=iterations",
+                "--junit",
+                "target/junit.xml",
+                "--format",
+                "json",
+            ]))
+            .unwrap();
+        assert_eq!(config.outliers.method, OutlierMethod::None);
+        assert!(config.outliers.exclude);
+        assert_eq!(config.timeout, Some(10));
+        assert_eq!(config.isolation.mode, IsolationMode::Process);
+        assert_eq!(config.child_test, Some("tests::some_test".to_string()));
+        assert_eq!(config.test_threads(), 4);
+        assert!(config.regression.enabled);
+        assert_eq!(config.regression.history, 3);
+        assert_eq!(config.results_dir, Some(PathBuf::from("/tmp/results")));
+        assert_eq!(config.csv, Some(CsvRows::Iterations));
+        assert_eq!(config.junit, Some(PathBuf::from("target/junit.xml")));
+        assert_eq!(config.format, OutputFormat::Json);
+        assert_eq!(Config::default().test_threads(), 1);
+    }
+
+    #[test]
+    fn config_arguments_inline_value() {
+        let mut config = Config::default();
+        config
+            .apply_arguments(arguments(&["some_filter", "--outliers=mad"]))
+            .unwrap();
+        assert_eq!(config.outliers.method, OutlierMethod::Mad);
+    }
+
+    #[test]
+    fn config_arguments_invalid_value() {
+        let mut config = Config::default();
+        assert!(config
+            .apply_arguments(arguments(&["--outliers", "grubbs"]))
+            .is_err());
+        assert!(config.apply_arguments(arguments(&["--outliers"])).is_err());
+        assert!(config
+            .apply_arguments(arguments(&["--timeout", "soon"]))
+            .is_err());
+    }
+}
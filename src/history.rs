@@ -0,0 +1,297 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Read the results of earlier runs back, to analyse them with your own tooling. Every run is a
+// `JsonResult`, in the format that is described by `schema/coppers_results.schema.json`.
+// Results in an older format are upgraded when they are loaded.
+//
+// let history = History::load("target/coppers_results")?;
+// let main = history.filter(&Filter {
+//     branch: Some("main".to_string()),
+//     ..Filter::default()
+// });
+// for point in main.series("tests::parse_large_file") {
+//     println!("{} {:.0} μJ", point.execution_timestamp, point.uj);
+// }
+
+use crate::test_runner::{JsonStore, Query, ResultStore};
+use std::path::Path;
+
+pub use crate::test_runner::{CompletedTest, Environment, GitInfo, JsonResult, TestResult};
+
+// The runs in a results directory, from the oldest to the latest one
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    runs: Vec<JsonResult>,
+}
+
+// Which runs are kept by `History::filter`. A run has to match everything that is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub branch: Option<String>,
+    pub hostname: Option<String>,
+    // The hash of the commit, or the start of it like a short hash
+    pub commit: Option<String>,
+    // Runs that are executed in this time range, in seconds since the epoch
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+// The energy and time per iteration of a test in a single run
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesPoint {
+    pub run_id: String,
+    pub execution_timestamp: u64,
+    pub commit: Option<String>,
+    pub uj: f64,
+    pub us: f64,
+}
+
+// The energy and time per iteration of a test in two runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestComparison {
+    pub name: String,
+    pub binary: Option<String>,
+    pub before_uj: f64,
+    pub after_uj: f64,
+    pub before_us: f64,
+    pub after_us: f64,
+}
+
+impl History {
+    // All runs in the results directory that can be read. The runs in the SQLite store of the
+    // directory are included as well when the `sqlite` feature is enabled.
+    pub fn load(directory: impl AsRef<Path>) -> Result<History, String> {
+        let directory = directory.as_ref();
+        #[allow(unused_mut)]
+        let mut runs = JsonStore::new(directory).runs(&Query::default())?;
+        #[cfg(feature = "sqlite")]
+        if directory
+            .join(crate::test_runner::DATABASE_FILE_NAME)
+            .is_file()
+        {
+            let store = crate::test_runner::SqliteStore::open(directory)?;
+            for run in store.runs(&Query::default())? {
+                if !runs.iter().any(|known| known.run_id() == run.run_id()) {
+                    runs.push(run);
+                }
+            }
+        }
+        runs.sort_by_key(JsonResult::execution_timestamp);
+        Ok(History { runs })
+    }
+
+    pub fn runs(&self) -> &[JsonResult] {
+        &self.runs
+    }
+
+    pub fn latest(&self) -> Option<&JsonResult> {
+        self.runs.last()
+    }
+
+    pub fn run(&self, run_id: &str) -> Option<&JsonResult> {
+        self.runs.iter().find(|run| run.run_id() == run_id)
+    }
+
+    pub fn filter(&self, filter: &Filter) -> History {
+        History {
+            runs: self
+                .runs
+                .iter()
+                .filter(|run| filter.matches(run))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    // The energy and time per iteration of a test in every run in which it ran completely. The
+    // test is either named by its name, or by the name of its test binary and its name, like
+    // `integration::tests::parse_large_file`.
+    pub fn series(&self, test: &str) -> Vec<SeriesPoint> {
+        self.runs
+            .iter()
+            .flat_map(|run| {
+                run.tests()
+                    .iter()
+                    .filter(|completed| is_named(completed, test))
+                    .filter_map(move |completed| {
+                        Some(SeriesPoint {
+                            run_id: run.run_id().to_string(),
+                            execution_timestamp: run.execution_timestamp(),
+                            commit: run.git().and_then(|git| git.head.clone()),
+                            uj: completed.average_uj()?,
+                            us: completed.average_us()?,
+                        })
+                    })
+            })
+            .collect()
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, run: &JsonResult) -> bool {
+        let git = run.git();
+        let branch = git.and_then(|git| git.branch.as_deref());
+        let head = git.and_then(|git| git.head.as_deref());
+        self.branch.as_deref().is_none_or(|b| branch == Some(b))
+            && self
+                .hostname
+                .as_deref()
+                .is_none_or(|hostname| run.environment().hostname == hostname)
+            && self.commit.as_deref().is_none_or(|commit| {
+                !commit.is_empty() && head.is_some_and(|head| head.starts_with(commit))
+            })
+            && self
+                .since
+                .is_none_or(|since| run.execution_timestamp() >= since)
+            && self
+                .until
+                .is_none_or(|until| run.execution_timestamp() <= until)
+    }
+}
+
+impl TestComparison {
+    // Change of the energy per iteration, in percent
+    pub fn uj_change(&self) -> f64 {
+        (self.after_uj - self.before_uj) / self.before_uj * 100.0
+    }
+
+    pub fn us_change(&self) -> f64 {
+        (self.after_us - self.before_us) / self.before_us * 100.0
+    }
+}
+
+// Compare the tests that ran completely in both runs
+pub fn compare(before: &JsonResult, after: &JsonResult) -> Vec<TestComparison> {
+    after
+        .tests()
+        .iter()
+        .filter_map(|test| {
+            let old = before.tests().iter().find(|old| same_test(old, test))?;
+            Some(TestComparison {
+                name: test.name().to_string(),
+                binary: test.binary().map(str::to_string),
+                before_uj: old.average_uj()?,
+                after_uj: test.average_uj()?,
+                before_us: old.average_us()?,
+                after_us: test.average_us()?,
+            })
+        })
+        .collect()
+}
+
+fn is_named(test: &CompletedTest, name: &str) -> bool {
+    test.name() == name
+        || test
+            .binary()
+            .and_then(|binary| name.strip_prefix(binary)?.strip_prefix("::"))
+            .is_some_and(|name| name == test.name())
+}
+
+// Older results do not know which binary a test is part of
+fn same_test(a: &CompletedTest, b: &CompletedTest) -> bool {
+    a.name() == b.name()
+        && (a.binary().is_none() || b.binary().is_none() || a.binary() == b.binary())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    fn run(timestamp: u64, branch: &str, head: &str, uj: u64) -> String {
+        serde_json::json!({
+            "schema_version": 1,
+            "run_id": format!("run-{timestamp}"),
+            "invocation_id": "invocation",
+            "execution_timestamp": timestamp,
+            "environment": { "hostname": "build-server" },
+            "git": { "head": head, "branch": branch, "dirty": false },
+            "total_us": 0, "total_uj": 0, "overhead_us": 0, "overhead_uj": 0,
+            "number_of_repeats": 10,
+            "tests": [
+                {
+                    "name": "tests::a", "binary": "integration", "state": "Passed",
+                    "uj": uj, "us": 100, "counted_repeats": 10, "outliers": 0, "iterations": []
+                },
+                {
+                    "name": "tests::b", "state": { "Failed": null },
+                    "uj": null, "us": null, "counted_repeats": 0, "outliers": 0, "iterations": []
+                }
+            ]
+        })
+        .to_string()
+    }
+
+    fn history() -> History {
+        let directory =
+            std::env::temp_dir().join(format!("coppers-history-{}", std::process::id()));
+        create_dir_all(&directory).unwrap();
+        let runs = [
+            run(30, "main", "c3", 3000),
+            run(10, "main", "a1", 1000),
+            run(20, "feature", "b2", 2000),
+        ];
+        for (i, run) in runs.iter().enumerate() {
+            write(directory.join(format!("coppers_results-{i}.json")), run).unwrap();
+        }
+        let history = History::load(&directory).unwrap();
+        remove_dir_all(directory).unwrap();
+        history
+    }
+
+    #[test]
+    fn load_and_filter() {
+        let history = history();
+        assert_eq!(history.runs().len(), 3);
+        assert_eq!(history.latest().unwrap().run_id(), "run-30");
+        assert!(history.run("run-20").is_some());
+
+        let main = history.filter(&Filter {
+            branch: Some("main".to_string()),
+            ..Filter::default()
+        });
+        assert_eq!(main.runs().len(), 2);
+        let commit = history.filter(&Filter {
+            commit: Some("b".to_string()),
+            hostname: Some("build-server".to_string()),
+            ..Filter::default()
+        });
+        assert_eq!(commit.runs().len(), 1);
+        let range = history.filter(&Filter {
+            since: Some(15),
+            until: Some(25),
+            ..Filter::default()
+        });
+        assert_eq!(range.runs()[0].run_id(), "run-20");
+    }
+
+    #[test]
+    fn series_and_comparison() {
+        let history = history();
+        let series = history.series("integration::tests::a");
+        assert_eq!(series.len(), 3);
+        assert_eq!(series[0].uj, 100.0);
+        assert_eq!(series[2].commit.as_deref(), Some("c3"));
+        assert_eq!(history.series("tests::a").len(), 3);
+        // A test that failed did not run completely
+        assert!(history.series("tests::b").is_empty());
+
+        let comparison = compare(&history.runs()[0], &history.runs()[2]);
+        assert_eq!(comparison.len(), 1);
+        assert_eq!(comparison[0].before_uj, 100.0);
+        assert_eq!(comparison[0].after_uj, 300.0);
+        assert_eq!(comparison[0].uj_change(), 200.0);
+    }
+}
@@ -12,18 +12,58 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#![feature(test)]
-#![feature(internal_output_capture)]
+// The unstable features are only used with a nightly compiler, see `build.rs`
+#![cfg_attr(coppers_nightly, feature(test))]
+#![cfg_attr(coppers_nightly, feature(internal_output_capture))]
 
 // Normally, we can use `use` to import a crate. However, the `test` crate is shipped
 // with Rust itself and thus it needs to be imported with `extern crate` because it is
 // a so called `sysroot` crate.
 // See https://doc.rust-lang.org/edition-guide/rust-2018/path-changes.html for more
 // information.
+#[cfg(coppers_nightly)]
 extern crate test;
 
-mod sensors;
+mod baseline;
+#[doc(hidden)]
+pub mod cli;
+mod config;
+pub mod history;
+mod measure;
+mod region;
+mod registry;
+pub mod sensors;
 mod test_runner;
 
 // Export the runner funcion so crates that depend on this crate can use it
-pub use crate::test_runner::runner;
+#[cfg(coppers_nightly)]
+pub use crate::test_runner::{runner, test_main};
+
+// Export the entry point of test targets with `harness = false`, which also works on stable
+pub use crate::test_runner::main;
+
+// Export the functions to measure code outside of tests
+pub use crate::measure::{measure, EnergyGuard, Measurement};
+
+// Export the functions to split tests into regions that are measured separately
+pub use crate::region::{exclude_from_measurement, region, ExcludeGuard};
+
+// Export the attribute that sets the Coppers settings of a test
+pub use coppers_macros::test;
+
+// Used by the code that `#[coppers::test]` generates, not meant to be used directly
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::registry::{ShouldPanic, TestReturn, TestSettings};
+    pub use crate::test_runner::set_budget;
+    pub use inventory;
+}
+
+// Set the amount of energy the current test may consume per iteration, in μJ. A test that
+// passes, but consumes more energy than its budget, is reported as over budget.
+#[macro_export]
+macro_rules! budget {
+    ($uj:expr) => {
+        $crate::__private::set_budget($uj)
+    };
+}
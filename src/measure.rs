@@ -0,0 +1,146 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Measure the energy consumption of code outside of the test runner, like in benchmarks,
+// examples or production code. The sensor is chosen automatically, and only time is measured
+// when energy cannot be measured on the current machine.
+
+use crate::sensors::{default_sensor, Sensor};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Measurement {
+    // Consumed energy in μJ, or `None` if energy cannot be measured on this machine
+    pub uj: Option<u128>,
+    pub duration: Duration,
+}
+
+impl Measurement {
+    fn of(sensor: &dyn Sensor) -> Measurement {
+        Measurement {
+            uj: sensor.measures_energy().then(|| sensor.get_measured_uj()),
+            duration: sensor.get_duration(),
+        }
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.uj {
+            Some(uj) => write!(f, "{uj} μJ in {} μs", self.duration.as_micros()),
+            None => write!(f, "{} μs", self.duration.as_micros()),
+        }
+    }
+}
+
+// Run a closure and measure the energy and time it consumes
+//
+// let (result, measurement) = coppers::measure(|| work());
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, Measurement) {
+    let mut sensor = default_sensor();
+    sensor.start_measuring();
+    let result = f();
+    sensor.stop_measuring();
+    (result, Measurement::of(sensor.as_ref()))
+}
+
+// Measures the energy and time from its creation until it is dropped, after which the
+// measurement is passed to the callback. Use `finish` to get the measurement directly instead.
+//
+// let _guard = coppers::EnergyGuard::new(|m| eprintln!("handled request in {m}"));
+pub struct EnergyGuard<F: FnOnce(Measurement)> {
+    sensor: Box<dyn Sensor + Send>,
+    callback: Option<F>,
+}
+
+impl<F: FnOnce(Measurement)> EnergyGuard<F> {
+    pub fn new(callback: F) -> EnergyGuard<F> {
+        let mut sensor = default_sensor();
+        sensor.start_measuring();
+        EnergyGuard {
+            sensor,
+            callback: Some(callback),
+        }
+    }
+
+    // Stop measuring without calling the callback
+    pub fn finish(mut self) -> Measurement {
+        self.callback = None;
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Measurement {
+        self.sensor.stop_measuring();
+        Measurement::of(self.sensor.as_ref())
+    }
+}
+
+impl<F: FnOnce(Measurement)> Drop for EnergyGuard<F> {
+    fn drop(&mut self) {
+        if let Some(callback) = self.callback.take() {
+            let measurement = self.stop();
+            callback(measurement);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::thread::sleep;
+
+    #[test]
+    fn measure_returns_result() {
+        let (result, measurement) = measure(|| {
+            sleep(Duration::from_millis(10));
+            42
+        });
+        assert_eq!(result, 42);
+        assert!(measurement.duration >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn energy_guard_calls_callback_on_drop() {
+        let measured = Cell::new(None);
+        {
+            let _guard = EnergyGuard::new(|m| measured.set(Some(m)));
+            sleep(Duration::from_millis(10));
+        }
+        assert!(measured.get().unwrap().duration >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn energy_guard_finish() {
+        let called = Cell::new(false);
+        let guard = EnergyGuard::new(|_| called.set(true));
+        guard.finish();
+        assert!(!called.get());
+    }
+
+    #[test]
+    fn measurement_display() {
+        let measurement = Measurement {
+            uj: None,
+            duration: Duration::from_micros(1500),
+        };
+        assert_eq!(measurement.to_string(), "1500 μs");
+        let measurement = Measurement {
+            uj: Some(20),
+            ..measurement
+        };
+        assert_eq!(measurement.to_string(), "20 μJ in 1500 μs");
+    }
+}
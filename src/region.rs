@@ -0,0 +1,273 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Regions split a test into named parts that are measured separately, and parts of a test can
+// be excluded from its measurement, like the setup of fixtures. The runner records the regions
+// of every iteration of a test on the thread that runs the test. Regions on other threads, or
+// outside of the runner, are not measured at all.
+
+use crate::sensors::{default_sensor, Sensor};
+use std::cell::RefCell;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct RegionMeasurement {
+    // Names of the nested regions, separated by a `/`
+    pub(crate) name: String,
+    pub(crate) uj: u128,
+    pub(crate) us: u128,
+    // Amount of times the region ran
+    pub(crate) calls: usize,
+}
+
+// Everything that is recorded during a single iteration of a test
+#[derive(Debug, Default)]
+pub(crate) struct Recording {
+    pub(crate) regions: Vec<RegionMeasurement>,
+    // Energy and time of the regions that are not nested in another region
+    pub(crate) top_level_uj: u128,
+    pub(crate) top_level_us: u128,
+    pub(crate) excluded_uj: u128,
+    pub(crate) excluded_us: u128,
+    // Regions that are running, from the outermost to the innermost one
+    stack: Vec<ActiveRegion>,
+}
+
+#[derive(Debug)]
+struct ActiveRegion {
+    name: String,
+    // Energy and time that are excluded while the region runs
+    excluded_uj: u128,
+    excluded_us: u128,
+}
+
+thread_local! {
+    static RECORDING: RefCell<Option<Recording>> = const { RefCell::new(None) };
+}
+
+// Run a closure as a named region of the current test, of which the energy and time is reported
+// separately. Regions can be nested.
+//
+// coppers::region("parse", || parse(&input));
+pub fn region<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    let Some(path) = with_recording(|recording| recording.enter(name)) else {
+        return f();
+    };
+
+    let mut sensor = default_sensor();
+    sensor.start_measuring();
+    let result = f();
+    sensor.stop_measuring();
+
+    with_recording(|recording| {
+        recording.leave(path, sensor.get_measured_uj(), sensor.get_elapsed_time_us())
+    });
+    result
+}
+
+// Exclude everything until the guard is dropped from the measurement of the current test
+//
+// let server = {
+//     let _excluded = coppers::exclude_from_measurement();
+//     spawn_server()
+// };
+pub fn exclude_from_measurement() -> ExcludeGuard {
+    let sensor = with_recording(|_| {
+        let mut sensor = default_sensor();
+        sensor.start_measuring();
+        sensor
+    });
+    ExcludeGuard { sensor }
+}
+
+pub struct ExcludeGuard {
+    // Only set when the guard is created while a test is being measured
+    sensor: Option<Box<dyn Sensor + Send>>,
+}
+
+impl Drop for ExcludeGuard {
+    fn drop(&mut self) {
+        if let Some(sensor) = &mut self.sensor {
+            sensor.stop_measuring();
+            with_recording(|recording| {
+                recording.exclude(sensor.get_measured_uj(), sensor.get_elapsed_time_us())
+            });
+        }
+    }
+}
+
+// Start recording the regions of an iteration of a test on the current thread
+pub(crate) fn start_recording() {
+    RECORDING.with(|recording| *recording.borrow_mut() = Some(Recording::default()));
+}
+
+// Stop recording, and return everything that is recorded since it started
+pub(crate) fn stop_recording() -> Recording {
+    RECORDING
+        .with(|recording| recording.borrow_mut().take())
+        .unwrap_or_default()
+}
+
+fn with_recording<T>(f: impl FnOnce(&mut Recording) -> T) -> Option<T> {
+    RECORDING.with(|recording| recording.borrow_mut().as_mut().map(f))
+}
+
+impl Recording {
+    // Start a region in the innermost running region. Returns the path of the region.
+    fn enter(&mut self, name: &str) -> String {
+        self.stack.push(ActiveRegion {
+            name: name.to_string(),
+            excluded_uj: 0,
+            excluded_us: 0,
+        });
+        let names: Vec<_> = self
+            .stack
+            .iter()
+            .map(|region| region.name.as_str())
+            .collect();
+        names.join("/")
+    }
+
+    // End the innermost running region, without what was excluded while it ran
+    fn leave(&mut self, path: String, uj: u128, us: u128) {
+        let region = self.stack.pop().expect("a region is running");
+        self.add(
+            path,
+            uj.saturating_sub(region.excluded_uj),
+            us.saturating_sub(region.excluded_us),
+        );
+    }
+
+    // Excluded energy and time are not part of the test, nor of any of the running regions
+    fn exclude(&mut self, uj: u128, us: u128) {
+        self.excluded_uj += uj;
+        self.excluded_us += us;
+        for region in &mut self.stack {
+            region.excluded_uj += uj;
+            region.excluded_us += us;
+        }
+    }
+
+    fn add(&mut self, name: String, uj: u128, us: u128) {
+        if self.stack.is_empty() {
+            self.top_level_uj += uj;
+            self.top_level_us += us;
+        }
+        add_region(&mut self.regions, name, uj, us, 1);
+    }
+}
+
+// Add the energy and time of a region to the region with the same name, in the order in which
+// the regions ran for the first time
+pub(crate) fn add_region(
+    regions: &mut Vec<RegionMeasurement>,
+    name: String,
+    uj: u128,
+    us: u128,
+    calls: usize,
+) {
+    match regions.iter_mut().find(|region| region.name == name) {
+        Some(region) => {
+            region.uj += uj;
+            region.us += us;
+            region.calls += calls;
+        }
+        None => regions.push(RegionMeasurement {
+            name,
+            uj,
+            us,
+            calls,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_are_not_recorded_outside_of_a_test() {
+        assert_eq!(region("parse", || 42), 42);
+        drop(exclude_from_measurement());
+        assert!(stop_recording().regions.is_empty());
+    }
+
+    #[test]
+    fn regions_are_recorded() {
+        start_recording();
+        region("setup", || {});
+        region("parse", || region("tokenize", || {}));
+        region("parse", || {});
+        drop(exclude_from_measurement());
+        let recording = stop_recording();
+
+        let names: Vec<_> = recording
+            .regions
+            .iter()
+            .map(|r| (r.name.as_str(), r.calls))
+            .collect();
+        assert_eq!(names, [("setup", 1), ("parse/tokenize", 1), ("parse", 2)]);
+        assert!(recording.stack.is_empty());
+        assert!(stop_recording().regions.is_empty());
+    }
+
+    #[test]
+    fn top_level_regions_exclude_nested_regions() {
+        let mut recording = Recording::default();
+        let parse = recording.enter("parse");
+        let tokenize = recording.enter("tokenize");
+        assert_eq!(tokenize, "parse/tokenize");
+        recording.leave(tokenize, 5, 1);
+        recording.leave(parse, 10, 1);
+        recording.add("parse".to_string(), 20, 2);
+        assert_eq!(recording.top_level_uj, 30);
+        assert_eq!(recording.top_level_us, 3);
+        assert_eq!(recording.regions[1].uj, 30);
+    }
+
+    #[test]
+    fn exclusions_are_subtracted_from_running_regions() {
+        let mut recording = Recording::default();
+        let parse = recording.enter("parse");
+        let tokenize = recording.enter("tokenize");
+        recording.exclude(4, 2);
+        recording.leave(tokenize, 5, 3);
+        recording.exclude(1, 1);
+        recording.leave(parse, 10, 6);
+        recording.exclude(100, 100);
+
+        let regions: Vec<_> = recording
+            .regions
+            .iter()
+            .map(|r| (r.name.as_str(), r.uj, r.us))
+            .collect();
+        assert_eq!(regions, [("parse/tokenize", 1, 1), ("parse", 5, 3)]);
+        assert_eq!(recording.top_level_uj, 5);
+        assert_eq!(recording.top_level_us, 3);
+        assert_eq!(recording.excluded_uj, 105);
+        assert_eq!(recording.excluded_us, 103);
+    }
+
+    #[test]
+    fn exclusion_nested_in_region() {
+        start_recording();
+        region("parse", || {
+            let _excluded = exclude_from_measurement();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        });
+        let recording = stop_recording();
+        assert!(recording.excluded_us >= 20_000);
+        assert!(recording.regions[0].us < recording.excluded_us);
+        assert_eq!(recording.top_level_us, recording.regions[0].us);
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Every test that is marked with `#[coppers::test(...)]` registers its settings here, before
+// `main` runs. The runner looks up the settings of a test by its name. The test function itself
+// is registered as well, so `coppers::main` can run the tests without the help of libtest.
+
+use std::fmt::Debug;
+
+// The settings of a single test, as they are written in its attribute
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct TestSettings {
+    pub module_path: &'static str,
+    pub name: &'static str,
+    pub repeats: Option<usize>,
+    pub warmup: Option<usize>,
+    pub budget_uj: Option<u128>,
+    pub group: Option<&'static str>,
+    pub ignore: bool,
+    pub should_panic: ShouldPanic,
+    pub function: fn() -> Result<(), String>,
+}
+
+// Whether a test should panic, as it is written in its `#[should_panic]` attribute
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShouldPanic {
+    No,
+    Yes,
+    YesWithMessage(&'static str),
+}
+
+// The return types that a test function can have, just like with libtest
+#[doc(hidden)]
+pub trait TestReturn {
+    fn into_result(self) -> Result<(), String>;
+}
+
+impl TestReturn for () {
+    fn into_result(self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl<E: Debug> TestReturn for Result<(), E> {
+    fn into_result(self) -> Result<(), String> {
+        self.map_err(|e| format!("Error: {e:?}"))
+    }
+}
+
+inventory::collect!(TestSettings);
+
+impl TestSettings {
+    pub(crate) fn find(test_name: &str) -> Option<&'static TestSettings> {
+        inventory::iter::<TestSettings>
+            .into_iter()
+            .find(|settings| settings.test_name() == test_name)
+    }
+
+    // All registered tests, sorted by their name
+    pub(crate) fn all() -> Vec<&'static TestSettings> {
+        let mut tests: Vec<_> = inventory::iter::<TestSettings>.into_iter().collect();
+        tests.sort_by_key(|settings| settings.test_name());
+        tests
+    }
+
+    // The name of a test does not include the name of the crate it is part of, which is the
+    // first segment of its module path
+    pub(crate) fn test_name(&self) -> String {
+        match self.module_path.split_once("::") {
+            Some((_, module)) => format!("{module}::{}", self.name),
+            None => self.name.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(module_path: &'static str, name: &'static str) -> TestSettings {
+        TestSettings {
+            module_path,
+            name,
+            repeats: None,
+            warmup: None,
+            budget_uj: None,
+            group: None,
+            ignore: false,
+            should_panic: ShouldPanic::No,
+            function: || Ok(()),
+        }
+    }
+
+    #[test]
+    fn test_name_without_crate() {
+        assert_eq!(
+            settings("coppers::test_runner::tests", "some_test").test_name(),
+            "test_runner::tests::some_test"
+        );
+        assert_eq!(
+            settings("integration", "some_test").test_name(),
+            "some_test"
+        );
+    }
+
+    #[test]
+    fn test_return_into_result() {
+        assert_eq!(().into_result(), Ok(()));
+        assert_eq!(Ok::<(), &str>(()).into_result(), Ok(()));
+        assert_eq!(
+            Err::<(), &str>("invalid").into_result(),
+            Err("Error: \"invalid\"".to_string())
+        );
+    }
+}
@@ -18,6 +18,9 @@ use std::option::Option;
 use std::result::Result;
 use std::time::{Duration, Instant};
 
+// Location of the RAPL sensor of the first CPU package
+pub(crate) const RAPL_LOCATION: &str = "/sys/devices/virtual/powercap/intel-rapl/intel-rapl:0";
+
 // Trait for all kind of sensors to implement
 pub trait Sensor {
     // Start position to measure the power consumption and timer
@@ -30,6 +33,28 @@ pub trait Sensor {
     fn get_elapsed_time_us(&self) -> u128;
     // Retrieve a duration value instead of seconds directly
     fn get_duration(&self) -> Duration;
+    // Whether the sensor measures energy, or only time
+    fn measures_energy(&self) -> bool {
+        true
+    }
+    // Short name of the sensor, which is recorded with the results
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
+}
+
+// Pick the most accurate sensor that is available on this machine. Falls back to a sensor that
+// only measures time when energy cannot be measured.
+pub fn default_sensor() -> Box<dyn Sensor + Send> {
+    match RAPLSensor::new(RAPL_LOCATION.to_string()) {
+        Ok(sensor) => Box::new(sensor),
+        Err(_) => Box::new(TimeSensor::default()),
+    }
+}
+
+// The reason why energy cannot be measured on this machine, if it cannot
+pub fn energy_unavailable_reason() -> Option<String> {
+    RAPLSensor::new(RAPL_LOCATION.to_string()).err()
 }
 
 #[derive(Debug)]
@@ -92,6 +117,50 @@ impl Sensor for RAPLSensor {
         let end = self.timer_end_position.unwrap();
         end.saturating_duration_since(start)
     }
+
+    fn name(&self) -> &'static str {
+        "rapl"
+    }
+}
+
+// Sensor that only measures time, for machines on which energy cannot be measured
+#[derive(Debug, Default)]
+pub struct TimeSensor {
+    timer_start_position: Option<Instant>,
+    timer_end_position: Option<Instant>,
+}
+
+impl Sensor for TimeSensor {
+    fn start_measuring(&mut self) {
+        self.timer_start_position = Some(Instant::now());
+    }
+
+    fn stop_measuring(&mut self) {
+        self.timer_end_position = Some(Instant::now());
+    }
+
+    fn get_measured_uj(&self) -> u128 {
+        0
+    }
+
+    fn get_elapsed_time_us(&self) -> u128 {
+        self.get_duration().as_micros()
+    }
+
+    fn get_duration(&self) -> Duration {
+        match (self.timer_start_position, self.timer_end_position) {
+            (Some(start), Some(end)) => end.saturating_duration_since(start),
+            _ => Duration::new(0, 0),
+        }
+    }
+
+    fn measures_energy(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        "time"
+    }
 }
 
 // Implementation of RAPLSensor sepcific functions
@@ -143,6 +212,23 @@ impl RAPLSensor {
     }
 }
 
+// Retrieve the CPU time that is spent by the current thread so far
+#[cfg(unix)]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // The pointer is valid for the duration of the call, because it points to a local variable
+    let result = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+    (result == 0).then(|| Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -179,13 +265,22 @@ mod tests {
         ));
         assert_eq!(result, 12345678901234567890123456789);
     }
+
+    #[test]
+    fn time_sensor_measures_time() {
+        let mut sensor = TimeSensor::default();
+        sensor.start_measuring();
+        sleep(Duration::from_millis(10));
+        sensor.stop_measuring();
+        assert!(sensor.get_duration() >= Duration::from_millis(10));
+        assert_eq!(sensor.get_measured_uj(), 0);
+        assert!(!sensor.measures_energy());
+    }
+
     #[test]
     #[ignore]
     fn test_rapl_sensor() {
-        let mut sensor = RAPLSensor::new(String::from(
-            "/sys/devices/virtual/powercap/intel-rapl/intel-rapl:0",
-        ))
-        .unwrap();
+        let mut sensor = RAPLSensor::new(RAPL_LOCATION.to_string()).unwrap();
         sensor.start_measuring();
         sleep(Duration::new(2, 0));
         sensor.stop_measuring();
@@ -0,0 +1,162 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// When tests run in parallel, the energy that is measured during a test also contains the
+// energy of the other tests that ran at the same time. Every test is attributed a share of its
+// measured energy, based on how much CPU time it spent compared to the other tests that
+// overlapped with it. The CPU time of the other tests is assumed to be spread evenly over their
+// runtime. Threads that are spawned by a test itself are not part of its CPU time.
+
+use super::{CompletedTest, Measurement};
+use std::time::Instant;
+
+pub(super) fn attribute(tests: &mut [CompletedTest]) {
+    let shares: Vec<_> = (0..tests.len()).map(|i| share(i, tests)).collect();
+    for (test, share) in tests.iter_mut().zip(shares) {
+        // Ignored tests did not run, so there is nothing to attribute
+        let Some(share) = share else {
+            continue;
+        };
+        let scale = |uj: u128| (uj as f64 * share).round() as u128;
+
+        test.package_uj = Some(test.consumed().0);
+        test.uj = test.uj.map(scale);
+        test.process_uj = test.process_uj.map(scale);
+        for iteration in &mut test.iterations {
+            iteration.uj = scale(iteration.uj);
+            iteration.excluded_uj = scale(iteration.excluded_uj);
+            for region in &mut iteration.regions {
+                region.uj = scale(region.uj);
+            }
+        }
+        for region in &mut test.regions {
+            region.uj = scale(region.uj);
+        }
+        test.measurement = Measurement::Attributed;
+    }
+}
+
+// The share of the measured energy that belongs to the test at `index`
+fn share(index: usize, tests: &[CompletedTest]) -> Option<f64> {
+    let test = &tests[index];
+    let (start, end) = test.window?;
+    let own = cpu_time_us(test)?;
+
+    let others: f64 = tests
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != index)
+        .filter_map(|(_, other)| {
+            let (other_start, other_end) = other.window?;
+            let overlap = overlap_us((start, end), (other_start, other_end));
+            let duration = other_end.duration_since(other_start).as_micros() as f64;
+            if overlap == 0.0 || duration == 0.0 {
+                return None;
+            }
+            Some(cpu_time_us(other)? * overlap / duration)
+        })
+        .sum();
+
+    if own + others == 0.0 {
+        return Some(1.0);
+    }
+    Some(own / (own + others))
+}
+
+// Tests that timed out did not report their CPU time, so they are assumed to have used the CPU
+// for their whole runtime
+fn cpu_time_us(test: &CompletedTest) -> Option<f64> {
+    let (start, end) = test.window?;
+    let cpu_us = test
+        .cpu_us
+        .unwrap_or_else(|| end.duration_since(start).as_micros());
+    Some(cpu_us as f64)
+}
+
+fn overlap_us(a: (Instant, Instant), b: (Instant, Instant)) -> f64 {
+    let start = a.0.max(b.0);
+    let end = a.1.min(b.1);
+    end.saturating_duration_since(start).as_micros() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Iteration, TestResult};
+    use super::*;
+    use std::time::Duration;
+
+    fn test(start: Instant, from_ms: u64, to_ms: u64, cpu_ms: u128, uj: u128) -> CompletedTest {
+        CompletedTest {
+            state: TestResult::Passed,
+            uj: Some(uj),
+            us: Some(0),
+            counted_repeats: 1,
+            iterations: vec![Iteration {
+                uj,
+                us: 0,
+                ..Default::default()
+            }],
+            cpu_us: Some(cpu_ms * 1000),
+            window: Some((
+                start + Duration::from_millis(from_ms),
+                start + Duration::from_millis(to_ms),
+            )),
+            ..CompletedTest::empty("test".to_string())
+        }
+    }
+
+    #[test]
+    fn attribution_without_overlap_keeps_energy() {
+        let start = Instant::now();
+        let mut tests = vec![test(start, 0, 10, 10, 100), test(start, 10, 20, 10, 200)];
+        attribute(&mut tests);
+        assert_eq!(tests[0].uj, Some(100));
+        assert_eq!(tests[1].uj, Some(200));
+        assert_eq!(tests[1].measurement, Measurement::Attributed);
+    }
+
+    #[test]
+    fn attribution_splits_by_cpu_time() {
+        let start = Instant::now();
+        let mut tests = vec![test(start, 0, 10, 10, 100), test(start, 0, 10, 30, 100)];
+        attribute(&mut tests);
+        assert_eq!(tests[0].uj, Some(25));
+        assert_eq!(tests[0].iterations[0].uj, 25);
+        assert_eq!(tests[0].package_uj, Some(100));
+        assert_eq!(tests[1].uj, Some(75));
+    }
+
+    #[test]
+    fn attribution_partial_overlap() {
+        let start = Instant::now();
+        // The second test overlaps half of the first test, and spends 10 ms of CPU time in that
+        // half, so it gets a third of the energy that is measured during the first test.
+        let mut tests = vec![test(start, 0, 20, 20, 300), test(start, 10, 30, 20, 300)];
+        attribute(&mut tests);
+        assert_eq!(tests[0].uj, Some(200));
+    }
+
+    #[test]
+    fn attribution_skips_ignored_tests() {
+        let start = Instant::now();
+        let mut tests = vec![
+            test(start, 0, 10, 10, 100),
+            CompletedTest::empty("ignored".to_string()),
+        ];
+        attribute(&mut tests);
+        assert_eq!(tests[0].uj, Some(100));
+        assert_eq!(tests[1].uj, None);
+        assert_eq!(tests[1].measurement, Measurement::Exclusive);
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A test can have a budget of energy that it may consume per iteration. A test that passes, but
+// exceeds its budget, is over budget. The budget of a test can be set in its attribute, by
+// calling `coppers::budget!` in the test itself, or in `coppers.toml`, in increasing order of
+// precedence.
+
+use super::{CompletedTest, TestResult};
+use std::cell::Cell;
+
+thread_local! {
+    // The budget that is set by the test that runs on this thread
+    static BUDGET_UJ: Cell<Option<u128>> = const { Cell::new(None) };
+}
+
+// Set the budget of the test that runs on the current thread, in μJ per iteration
+pub fn set_budget(uj: u128) {
+    BUDGET_UJ.with(|budget| budget.set(Some(uj)));
+}
+
+// The budget that is set by the test that ran on the current thread
+pub(super) fn take_budget() -> Option<u128> {
+    BUDGET_UJ.with(Cell::take)
+}
+
+impl CompletedTest {
+    // The average energy of the counted iterations
+    pub(super) fn uj_per_iteration(&self) -> Option<u128> {
+        self.uj.map(|uj| uj / self.counted_repeats.max(1) as u128)
+    }
+
+    // The energy of a test can still change after it ran, for example when it is attributed a
+    // share of the energy of the tests that ran at the same time, so this can be checked again.
+    pub(super) fn check_budget(&mut self) {
+        if !matches!(self.state, TestResult::Passed | TestResult::OverBudget) {
+            return;
+        }
+        if let (Some(budget), Some(uj)) = (self.options.budget_uj, self.uj_per_iteration()) {
+            self.state = if uj > budget {
+                TestResult::OverBudget
+            } else {
+                TestResult::Passed
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test(state: TestResult, uj: u128, budget_uj: Option<u128>) -> CompletedTest {
+        let mut test = CompletedTest {
+            state,
+            uj: Some(uj),
+            us: Some(0),
+            counted_repeats: 10,
+            ..CompletedTest::empty("test".to_string())
+        };
+        test.options.budget_uj = budget_uj;
+        test
+    }
+
+    #[test]
+    fn check_budget_per_iteration() {
+        let mut under = test(TestResult::Passed, 1000, Some(100));
+        under.check_budget();
+        assert_eq!(under.state, TestResult::Passed);
+
+        let mut over = test(TestResult::Passed, 1010, Some(100));
+        over.check_budget();
+        assert_eq!(over.state, TestResult::OverBudget);
+    }
+
+    #[test]
+    fn check_budget_again() {
+        let mut test = test(TestResult::OverBudget, 500, Some(100));
+        test.check_budget();
+        assert_eq!(test.state, TestResult::Passed);
+    }
+
+    #[test]
+    fn check_budget_only_passed_tests() {
+        let mut failed = test(TestResult::Failed(None), 5000, Some(100));
+        failed.check_budget();
+        assert_eq!(failed.state, TestResult::Failed(None));
+
+        let mut without_budget = test(TestResult::Passed, 5000, None);
+        without_budget.check_budget();
+        assert_eq!(without_budget.state, TestResult::Passed);
+    }
+
+    #[test]
+    fn budget_of_current_thread() {
+        assert_eq!(take_budget(), None);
+        set_budget(42);
+        assert_eq!(take_budget(), Some(42));
+        assert_eq!(take_budget(), None);
+    }
+
+    // The runner reads back the result of a test that ran in its own process
+    #[test]
+    fn budget_survives_serialization() {
+        let test = test(TestResult::OverBudget, 5000, Some(100));
+        let json = serde_json::to_string(&test).unwrap();
+        assert_eq!(serde_json::from_str::<CompletedTest>(&json).unwrap(), test);
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Captures the output of a test, so it can be shown when the test fails. With a nightly
+// compiler, the internal compiler function `set_output_capture` captures the output of the
+// thread that runs the test. On stable, the standard output and standard error of the whole
+// process are redirected to a file instead. That captures the output of other threads as well,
+// so it is only done when a single test runs at a time.
+
+// Created by the runner before the test starts
+pub(super) struct Capture {
+    #[cfg(coppers_nightly)]
+    output: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    #[cfg(all(unix, not(coppers_nightly)))]
+    redirect: Option<redirect::Redirect>,
+}
+
+// Handed to the thread that runs the test
+pub(super) struct ThreadCapture {
+    #[cfg(coppers_nightly)]
+    output: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+}
+
+impl Capture {
+    // `exclusive` tells whether no other test runs at the same time
+    #[allow(unused_variables)]
+    pub(super) fn start(exclusive: bool) -> Capture {
+        Capture {
+            #[cfg(coppers_nightly)]
+            output: Default::default(),
+            // Output that cannot be captured is shown directly
+            #[cfg(all(unix, not(coppers_nightly)))]
+            redirect: exclusive
+                .then(redirect::Redirect::start)
+                .and_then(Result::ok),
+        }
+    }
+
+    // Run `f` with the original standard output and standard error, so the runner itself can
+    // write to them while the output of a test is redirected
+    pub(super) fn uncaptured<T>(&self, f: impl FnOnce() -> T) -> T {
+        #[cfg(all(unix, not(coppers_nightly)))]
+        if let Some(redirect) = &self.redirect {
+            return redirect.paused(f);
+        }
+        f()
+    }
+
+    pub(super) fn for_thread(&self) -> ThreadCapture {
+        ThreadCapture {
+            #[cfg(coppers_nightly)]
+            output: self.output.clone(),
+        }
+    }
+
+    // The output so far. The test might still be running if it timed out.
+    pub(super) fn finish(self) -> Vec<u8> {
+        #[cfg(coppers_nightly)]
+        let output = self
+            .output
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .to_vec();
+        #[cfg(all(unix, not(coppers_nightly)))]
+        let output = self.redirect.map(|r| r.finish()).unwrap_or_default();
+        #[cfg(all(not(unix), not(coppers_nightly)))]
+        let output = Vec::new();
+        output
+    }
+}
+
+impl ThreadCapture {
+    // Start capturing the output of the current thread
+    pub(super) fn install(self) {
+        #[cfg(coppers_nightly)]
+        std::io::set_output_capture(Some(self.output));
+    }
+
+    pub(super) fn uninstall() {
+        #[cfg(coppers_nightly)]
+        std::io::set_output_capture(None);
+    }
+}
+
+#[cfg(all(unix, not(coppers_nightly)))]
+mod redirect {
+    use std::env::temp_dir;
+    use std::fs::{remove_file, File, OpenOptions};
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CAPTURES: AtomicUsize = AtomicUsize::new(0);
+
+    // Standard output and standard error are redirected to a temporary file until `finish`
+    pub(super) struct Redirect {
+        file: File,
+        // Duplicates of the original standard output and standard error
+        stdout: libc::c_int,
+        stderr: libc::c_int,
+    }
+
+    impl Redirect {
+        pub(super) fn start() -> io::Result<Redirect> {
+            let path = temp_dir().join(format!(
+                "coppers-output-{}-{}",
+                process::id(),
+                CAPTURES.fetch_add(1, Ordering::Relaxed)
+            ));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            // The file stays readable through its descriptor
+            let _ = remove_file(&path);
+
+            let _ = io::stdout().flush();
+            // SAFETY: only duplicates and replaces file descriptors that are owned by this
+            // process, the duplicates are closed again in `finish`
+            unsafe {
+                let stdout = libc::dup(libc::STDOUT_FILENO);
+                let stderr = libc::dup(libc::STDERR_FILENO);
+                if stdout < 0 || stderr < 0 {
+                    let error = io::Error::last_os_error();
+                    libc::close(stdout);
+                    libc::close(stderr);
+                    return Err(error);
+                }
+                libc::dup2(file.as_raw_fd(), libc::STDOUT_FILENO);
+                libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO);
+                Ok(Redirect {
+                    file,
+                    stdout,
+                    stderr,
+                })
+            }
+        }
+
+        // Output of the test that is written while the redirect is paused is not captured
+        pub(super) fn paused<T>(&self, f: impl FnOnce() -> T) -> T {
+            let _ = io::stdout().flush();
+            // SAFETY: see `start`
+            unsafe {
+                libc::dup2(self.stdout, libc::STDOUT_FILENO);
+                libc::dup2(self.stderr, libc::STDERR_FILENO);
+            }
+            let result = f();
+            let _ = io::stdout().flush();
+            // SAFETY: see `start`
+            unsafe {
+                libc::dup2(self.file.as_raw_fd(), libc::STDOUT_FILENO);
+                libc::dup2(self.file.as_raw_fd(), libc::STDERR_FILENO);
+            }
+            result
+        }
+
+        // Restore the original standard output and standard error, and return everything that
+        // was written in the meantime
+        pub(super) fn finish(mut self) -> Vec<u8> {
+            let _ = io::stdout().flush();
+            // SAFETY: see `start`
+            unsafe {
+                libc::dup2(self.stdout, libc::STDOUT_FILENO);
+                libc::dup2(self.stderr, libc::STDERR_FILENO);
+                libc::close(self.stdout);
+                libc::close(self.stderr);
+            }
+            let mut output = Vec::new();
+            let _ = self.file.seek(SeekFrom::Start(0));
+            let _ = self.file.read_to_end(&mut output);
+            output
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_without_output() {
+        let capture = Capture::start(false);
+        capture.for_thread().install();
+        ThreadCapture::uninstall();
+        assert!(capture.finish().is_empty());
+    }
+
+    // The output is written to the standard output directly, because libtest captures what is
+    // printed by the tests
+    #[cfg(all(unix, not(coppers_nightly)))]
+    #[test]
+    fn runner_output_is_not_captured() {
+        use std::io::Write;
+
+        // The file that the standard output refers to
+        fn stdout_inode() -> libc::ino_t {
+            // SAFETY: `fstat` only writes to the zeroed struct
+            unsafe {
+                let mut stat: libc::stat = std::mem::zeroed();
+                libc::fstat(libc::STDOUT_FILENO, &mut stat);
+                stat.st_ino
+            }
+        }
+
+        let original = stdout_inode();
+        let capture = Capture::start(true);
+        std::io::stdout().write_all(b"test output\n").unwrap();
+        assert_eq!(capture.uncaptured(stdout_inode), original);
+        std::io::stdout().write_all(b"more test output\n").unwrap();
+        assert_ne!(stdout_inode(), original);
+        assert_eq!(capture.finish(), b"test output\nmore test output\n");
+    }
+}
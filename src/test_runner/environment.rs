@@ -0,0 +1,205 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The machine and toolchain that a run is executed with. The energy of a test depends on all of
+// them, so runs are only compared with runs in the same environment. Everything that cannot be
+// determined on the current machine is left out.
+
+use crate::sensors::default_sensor;
+use std::fs::read_to_string;
+use std::thread::available_parallelism;
+
+const CPUINFO: &str = "/proc/cpuinfo";
+const KERNEL_RELEASE: &str = "/proc/sys/kernel/osrelease";
+const SCALING_GOVERNOR: &str = "/sys/devices/system/cpu/cpu0/cpufreq/scaling_governor";
+// Set to 1 when the intel_pstate driver does not use turbo frequencies
+const INTEL_NO_TURBO: &str = "/sys/devices/system/cpu/intel_pstate/no_turbo";
+// Set to 1 when the acpi-cpufreq driver uses turbo frequencies
+const CPUFREQ_BOOST: &str = "/sys/devices/system/cpu/cpufreq/boost";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct Environment {
+    pub hostname: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cores: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub governor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub turbo: Option<bool>,
+    // The compiler, target and profile that Coppers is built with, which are the same as those
+    // of the tests, and the enabled features of Coppers
+    pub rustc: String,
+    pub target: String,
+    pub profile: String,
+    pub features: Vec<String>,
+    // The sensor that measured the run
+    pub sensor: String,
+}
+
+impl Environment {
+    pub(super) fn current() -> Environment {
+        let cpuinfo = read_to_string(CPUINFO).unwrap_or_default();
+        Environment {
+            hostname: hostname(),
+            cpu_model: cpu_model(&cpuinfo),
+            cores: cores(&cpuinfo).or_else(|| available_parallelism().ok().map(usize::from)),
+            kernel: read_trimmed(KERNEL_RELEASE),
+            governor: read_trimmed(SCALING_GOVERNOR),
+            turbo: turbo(),
+            rustc: env!("COPPERS_RUSTC_VERSION").to_string(),
+            target: env!("COPPERS_TARGET").to_string(),
+            profile: env!("COPPERS_PROFILE").to_string(),
+            features: env!("COPPERS_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(str::to_string)
+                .collect(),
+            sensor: default_sensor().name().to_string(),
+        }
+    }
+}
+
+impl Environment {
+    // Whether the energy that is measured in both environments is comparable. Only the hardware
+    // and how it is clocked, the target and profile of the tests, and the sensor are compared.
+    // The hostname, kernel, patch releases of the compiler and the features of Coppers differ
+    // between developers and CI runners with the same hardware, which would keep a baseline that
+    // is checked into the repository from being compared anywhere else.
+    pub(crate) fn comparable_with(&self, other: &Environment) -> bool {
+        self.cpu_model == other.cpu_model
+            && self.cores == other.cores
+            && self.governor == other.governor
+            && self.turbo == other.turbo
+            && self.target == other.target
+            && self.profile == other.profile
+            && self.sensor == other.sensor
+    }
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    read_to_string(path)
+        .ok()
+        .map(|contents| contents.trim().to_string())
+        .filter(|contents| !contents.is_empty())
+}
+
+fn cpu_model(cpuinfo: &str) -> Option<String> {
+    cpuinfo_values(cpuinfo, "model name")
+        .next()
+        .map(str::to_string)
+}
+
+// Every logical core has its own entry in `/proc/cpuinfo`
+fn cores(cpuinfo: &str) -> Option<usize> {
+    Some(cpuinfo_values(cpuinfo, "processor").count()).filter(|cores| *cores > 0)
+}
+
+fn cpuinfo_values<'a>(cpuinfo: &'a str, key: &'a str) -> impl Iterator<Item = &'a str> {
+    cpuinfo.lines().filter_map(move |line| {
+        let (name, value) = line.split_once(':')?;
+        (name.trim() == key).then(|| value.trim())
+    })
+}
+
+fn turbo() -> Option<bool> {
+    match read_trimmed(INTEL_NO_TURBO) {
+        Some(no_turbo) => Some(no_turbo == "0"),
+        None => read_trimmed(CPUFREQ_BOOST).map(|boost| boost == "1"),
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // The buffer is valid for its whole length, and the length leaves room for a terminating
+    // null byte
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len() - 1) };
+    if result != 0 {
+        return String::new();
+    }
+    let length = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPUINFO: &str = "processor\t: 0
+model name\t: Intel(R) Core(TM) i7-8550U CPU @ 1.80GHz
+cpu cores\t: 4
+
+processor\t: 1
+model name\t: Intel(R) Core(TM) i7-8550U CPU @ 1.80GHz
+cpu cores\t: 4
+";
+
+    #[test]
+    fn cpu_from_cpuinfo() {
+        assert_eq!(
+            cpu_model(CPUINFO).as_deref(),
+            Some("Intel(R) Core(TM) i7-8550U CPU @ 1.80GHz")
+        );
+        assert_eq!(cores(CPUINFO), Some(2));
+        assert_eq!(cpu_model(""), None);
+        assert_eq!(cores(""), None);
+    }
+
+    #[test]
+    fn comparable_environments() {
+        let here = Environment {
+            hostname: "here".to_string(),
+            cpu_model: Some("Intel(R) Core(TM) i7-8550U CPU @ 1.80GHz".to_string()),
+            kernel: Some("6.1.0".to_string()),
+            rustc: "rustc 1.80.0".to_string(),
+            sensor: "rapl".to_string(),
+            ..Environment::default()
+        };
+        let other_machine = Environment {
+            hostname: "ci".to_string(),
+            kernel: Some("6.8.0".to_string()),
+            rustc: "rustc 1.80.1".to_string(),
+            ..here.clone()
+        };
+        assert!(here.comparable_with(&other_machine));
+        let other_sensor = Environment {
+            sensor: "time".to_string(),
+            ..here.clone()
+        };
+        assert!(!here.comparable_with(&other_sensor));
+        let other_cpu = Environment {
+            cpu_model: None,
+            ..here.clone()
+        };
+        assert!(!here.comparable_with(&other_cpu));
+    }
+
+    #[test]
+    fn current_environment() {
+        let environment = Environment::current();
+        assert!(!environment.rustc.is_empty());
+        assert!(!environment.target.is_empty());
+        assert!(environment.cores.is_some());
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Flat exports of the results of all runs, to load them into spreadsheets and notebooks without
+// having to unnest the JSON results first. Every row is a test in a run, or an iteration of a
+// test in a run, together with the environment and the state of the repository of that run.
+
+use super::json::JsonResult;
+use super::location::export_directory;
+use super::store::{Query, ResultStore};
+use super::{CompletedTest, TestKind, TestResult};
+use crate::config::CsvRows;
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const RUN_COLUMNS: &[&str] = &[
+    "run_id",
+    "invocation_id",
+    "execution_timestamp",
+    "hostname",
+    "cpu_model",
+    "cores",
+    "kernel",
+    "governor",
+    "turbo",
+    "rustc",
+    "target",
+    "profile",
+    "features",
+    "sensor",
+    "git_head",
+    "git_branch",
+    "git_dirty",
+    "binary",
+    "test",
+    "kind",
+    "state",
+];
+const TEST_COLUMNS: &[&str] = &[
+    "uj",
+    "us",
+    "counted_repeats",
+    "outliers",
+    "uj_per_iteration",
+    "us_per_iteration",
+];
+const ITERATION_COLUMNS: &[&str] = &["iteration", "uj", "us", "outlier"];
+
+// Export all runs in the store to a CSV file in the export directory, from the oldest to the
+// latest run. Returns the location of the file.
+pub(crate) fn export_csv(
+    store: &dyn ResultStore,
+    rows: CsvRows,
+    results_directory: &Path,
+) -> Result<PathBuf, String> {
+    let mut runs = store.runs(&Query::default())?;
+    runs.reverse();
+
+    let directory = export_directory(results_directory);
+    create_dir_all(&directory)
+        .map_err(|e| format!("Could not create {}: {e}", directory.display()))?;
+    let path = directory.join(match rows {
+        CsvRows::Tests => "tests.csv",
+        CsvRows::Iterations => "iterations.csv",
+    });
+    let file =
+        File::create(&path).map_err(|e| format!("Could not create {}: {e}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write_csv(&runs, rows, &mut writer)
+        .and_then(|_| writer.flush())
+        .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+    Ok(path)
+}
+
+pub(crate) fn write_csv(
+    runs: &[JsonResult],
+    rows: CsvRows,
+    writer: &mut impl Write,
+) -> std::io::Result<()> {
+    let columns = match rows {
+        CsvRows::Tests => TEST_COLUMNS,
+        CsvRows::Iterations => ITERATION_COLUMNS,
+    };
+    let header: Vec<String> = RUN_COLUMNS
+        .iter()
+        .chain(columns)
+        .map(|column| column.to_string())
+        .collect();
+    write_row(writer, &header)?;
+
+    for run in runs {
+        for test in &run.tests {
+            let prefix = run_fields(run, test);
+            match rows {
+                CsvRows::Tests => {
+                    let fields = [
+                        optional(test.uj),
+                        optional(test.us),
+                        test.counted_repeats.to_string(),
+                        test.outliers.to_string(),
+                        optional(test.average_uj()),
+                        optional(test.average_us()),
+                    ];
+                    write_row(writer, &[prefix, fields.to_vec()].concat())?;
+                }
+                CsvRows::Iterations => {
+                    for (i, iteration) in test.iterations.iter().enumerate() {
+                        let fields = [
+                            i.to_string(),
+                            iteration.uj.to_string(),
+                            iteration.us.to_string(),
+                            iteration.outlier.to_string(),
+                        ];
+                        write_row(writer, &[prefix.clone(), fields.to_vec()].concat())?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// The columns that are the same for every row of a test in a run
+fn run_fields(run: &JsonResult, test: &CompletedTest) -> Vec<String> {
+    let environment = &run.environment;
+    let git = run.git.as_ref();
+    vec![
+        run.run_id.clone(),
+        run.invocation_id.clone(),
+        run.execution_timestamp.to_string(),
+        environment.hostname.clone(),
+        optional(environment.cpu_model.as_ref()),
+        optional(environment.cores),
+        optional(environment.kernel.as_ref()),
+        optional(environment.governor.as_ref()),
+        optional(environment.turbo),
+        environment.rustc.clone(),
+        environment.target.clone(),
+        environment.profile.clone(),
+        environment.features.join(";"),
+        environment.sensor.clone(),
+        optional(git.and_then(|git| git.head.as_ref())),
+        optional(git.and_then(|git| git.branch.as_ref())),
+        optional(git.map(|git| git.dirty)),
+        optional(test.binary.as_ref()),
+        test.name.clone(),
+        match test.kind {
+            TestKind::Test => "test",
+            TestKind::Bench => "bench",
+        }
+        .to_string(),
+        match test.state {
+            TestResult::Passed => "Passed",
+            TestResult::Failed(_) => "Failed",
+            TestResult::TimedOut => "TimedOut",
+            TestResult::OverBudget => "OverBudget",
+            TestResult::Ignored => "Ignored",
+        }
+        .to_string(),
+    ]
+}
+
+// Missing values are empty fields
+fn optional(value: Option<impl ToString>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn write_row(writer: &mut impl Write, fields: &[String]) -> std::io::Result<()> {
+    let fields: Vec<String> = fields.iter().map(|field| escape(field)).collect();
+    writeln!(writer, "{}", fields.join(","))
+}
+
+// Fields with a separator, quote or line break are quoted, as described by RFC 4180
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Iteration;
+    use super::*;
+
+    fn export(rows: CsvRows) -> Vec<String> {
+        let mut result = JsonResult::example(10, 100);
+        result.environment.features = vec!["sqlite".to_string(), "visualization".to_string()];
+        result.tests = vec![
+            CompletedTest {
+                state: TestResult::Passed,
+                uj: Some(30),
+                us: Some(3),
+                counted_repeats: 2,
+                iterations: vec![Iteration::default(), Iteration::default()],
+                ..CompletedTest::empty("tests::a".to_string())
+            },
+            CompletedTest {
+                state: TestResult::Failed(Some("a, \"b\"".to_string())),
+                ..CompletedTest::empty("tests::b".to_string())
+            },
+        ];
+        let mut output = Vec::new();
+        write_csv(&[result], rows, &mut output).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn export_tests() {
+        let lines = export(CsvRows::Tests);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("run_id,invocation_id,execution_timestamp,hostname,"));
+        assert!(lines[0]
+            .ends_with(",state,uj,us,counted_repeats,outliers,uj_per_iteration,us_per_iteration"));
+        let fields: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(fields.len(), RUN_COLUMNS.len() + TEST_COLUMNS.len());
+        assert_eq!(fields[0], "run-10");
+        assert_eq!(fields[12], "sqlite;visualization");
+        assert_eq!(
+            &fields[18..],
+            ["tests::a", "test", "Passed", "30", "3", "2", "0", "15", "1.5"]
+        );
+        assert!(lines[2].ends_with(",tests::b,test,Failed,,,0,0,,"));
+    }
+
+    #[test]
+    fn export_iterations() {
+        let lines = export(CsvRows::Iterations);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(",state,iteration,uj,us,outlier"));
+        assert!(lines[2].ends_with(",tests::a,test,Passed,1,0,0,false"));
+    }
+
+    #[test]
+    fn escape_fields() {
+        assert_eq!(escape("tests::a"), "tests::a");
+        assert_eq!(escape("a,b"), "\"a,b\"");
+        assert_eq!(escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
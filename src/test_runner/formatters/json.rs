@@ -0,0 +1,159 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The event stream of `--format json` of libtest, with a JSON object per line, so the tools that
+// read it keep working with Coppers. The events of a test carry its energy as extra fields, and
+// the event at the end of the suite the energy of the whole run. Messages that are not part of
+// the stream are written to the standard error instead.
+
+use super::{failure_message, is_failure, Formatter, Summary};
+use crate::test_runner::{CompletedTest, TestResult};
+use serde_json::{json, Map, Value};
+use std::io::{self, Write};
+use std::time::Duration;
+
+pub(super) struct JsonFormatter<W> {
+    out: W,
+}
+
+impl<W: Write> JsonFormatter<W> {
+    pub(super) fn new(out: W) -> Self {
+        JsonFormatter { out }
+    }
+
+    fn write_event(&mut self, event: Value) -> io::Result<()> {
+        writeln!(self.out, "{event}")?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Formatter for JsonFormatter<W> {
+    fn write_run_start(&mut self, tests: &[&str]) -> io::Result<()> {
+        self.write_event(json!({ "type": "suite", "event": "started", "test_count": tests.len() }))
+    }
+
+    fn write_message(&mut self, message: &str) -> io::Result<()> {
+        writeln!(io::stderr(), "{message}")
+    }
+
+    fn write_test_start(&mut self, name: &str) -> io::Result<()> {
+        self.write_event(json!({ "type": "test", "event": "started", "name": name }))
+    }
+
+    fn write_long_running(&mut self, name: &str, _elapsed: Duration) -> io::Result<()> {
+        self.write_event(json!({ "type": "test", "event": "timeout", "name": name }))
+    }
+
+    fn write_result(&mut self, test: &CompletedTest) -> io::Result<()> {
+        let event = match test.state {
+            TestResult::Passed => "ok",
+            TestResult::Ignored => "ignored",
+            _ => "failed",
+        };
+        let mut fields = Map::new();
+        fields.insert("type".to_string(), json!("test"));
+        fields.insert("name".to_string(), json!(test.name));
+        fields.insert("event".to_string(), json!(event));
+        if test.state != TestResult::Ignored {
+            fields.insert("exec_time".to_string(), json!(seconds(test.consumed().1)));
+        }
+        if is_failure(test) {
+            if let Some(captured) = &test.stdout {
+                fields.insert(
+                    "stdout".to_string(),
+                    json!(String::from_utf8_lossy(captured)),
+                );
+            }
+            if let Some(message) = failure_message(test) {
+                fields.insert("message".to_string(), json!(message));
+            }
+        }
+        if let Some(uj) = test.uj {
+            fields.insert("energy_uj".to_string(), json!(uj));
+        }
+        if let Some(us) = test.us {
+            fields.insert("time_us".to_string(), json!(us));
+        }
+        fields.insert("iterations".to_string(), json!(test.counted_repeats));
+        fields.insert("outliers".to_string(), json!(test.outliers));
+        if let Some(uj) = test.average_uj() {
+            fields.insert("energy_uj_per_iteration".to_string(), json!(uj));
+        }
+        if let Some(budget) = test.options.budget_uj {
+            fields.insert("budget_uj".to_string(), json!(budget));
+        }
+        self.write_event(Value::Object(fields))
+    }
+
+    fn write_run_finish(&mut self, summary: &Summary) -> io::Result<()> {
+        self.write_event(json!({
+            "type": "suite",
+            "event": if summary.success { "ok" } else { "failed" },
+            "passed": summary.count(|s| *s == TestResult::Passed),
+            "failed": summary.failures().len(),
+            "ignored": summary.count(|s| *s == TestResult::Ignored),
+            "measured": 0,
+            "filtered_out": 0,
+            "exec_time": seconds(summary.total_us),
+            "energy_uj": summary.total_uj,
+            "time_us": summary.total_us,
+            "test_energy_uj": summary.test_uj,
+            "test_time_us": summary.test_us,
+            "overhead_energy_uj": summary.overhead_uj,
+            "overhead_time_us": summary.overhead_us,
+            "regressed": summary.regressions.len(),
+            "baseline_deviations": summary.deviations.len(),
+        }))
+    }
+}
+
+fn seconds(us: u128) -> f64 {
+    us as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::write_example;
+    use super::*;
+
+    #[test]
+    fn libtest_events() {
+        let mut output = Vec::new();
+        write_example(&mut JsonFormatter::new(&mut output));
+
+        let events: Vec<Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 8);
+        assert_eq!(
+            events[0],
+            json!({ "type": "suite", "event": "started", "test_count": 3 })
+        );
+        assert_eq!(events[1]["event"], "started");
+        assert_eq!(events[2]["event"], "ok");
+        assert_eq!(events[2]["exec_time"], 0.0015);
+        assert_eq!(events[2]["energy_uj_per_iteration"], 15.0);
+        assert_eq!(events[4]["event"], "failed");
+        assert_eq!(events[4]["stdout"], "some output");
+        assert_eq!(events[4]["message"], "assertion failed");
+        assert_eq!(events[6]["event"], "ignored");
+        assert_eq!(events[7]["event"], "failed");
+        assert_eq!(events[7]["passed"], 1);
+        assert_eq!(events[7]["failed"], 1);
+        assert_eq!(events[7]["ignored"], 1);
+        assert_eq!(events[7]["energy_uj"], 100);
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// How the progress and the results of a run are written to the console, which is selected with
+// `format` in `coppers.toml` or `--format`, just like the formatters of libtest.
+
+use super::regression::Regression;
+use super::{CompletedTest, TestResult};
+use crate::baseline::Pending;
+use crate::config::OutputFormat;
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::time::Duration;
+
+mod json;
+mod pretty;
+mod quiet;
+mod tap;
+mod terse;
+
+use self::json::JsonFormatter;
+use self::pretty::PrettyFormatter;
+use self::quiet::QuietFormatter;
+use self::tap::TapFormatter;
+use self::terse::TerseFormatter;
+
+pub(super) trait Formatter {
+    fn write_run_start(&mut self, tests: &[&str]) -> io::Result<()>;
+    // Messages for the user that are not about a single test, like warnings
+    fn write_message(&mut self, message: &str) -> io::Result<()>;
+    fn write_test_start(&mut self, name: &str) -> io::Result<()>;
+    // A test that is still running after `elapsed`
+    fn write_long_running(&mut self, name: &str, elapsed: Duration) -> io::Result<()>;
+    fn write_result(&mut self, test: &CompletedTest) -> io::Result<()>;
+    fn write_run_finish(&mut self, summary: &Summary) -> io::Result<()>;
+}
+
+// Everything that is known at the end of a run
+pub(super) struct Summary<'a> {
+    pub(super) tests: &'a [CompletedTest],
+    pub(super) regressions: &'a [Regression],
+    // The amount of previous runs the tests are compared with, if regressions are checked
+    pub(super) history: Option<usize>,
+    pub(super) deviations: &'a [Pending],
+    pub(super) success: bool,
+    pub(super) total_uj: u128,
+    pub(super) total_us: u128,
+    pub(super) test_uj: u128,
+    pub(super) test_us: u128,
+    pub(super) overhead_uj: u128,
+    pub(super) overhead_us: u128,
+}
+
+impl Summary<'_> {
+    pub(super) fn count(&self, predicate: impl Fn(&TestResult) -> bool) -> usize {
+        self.tests.iter().filter(|t| predicate(&t.state)).count()
+    }
+
+    pub(super) fn failures(&self) -> Vec<&CompletedTest> {
+        self.tests.iter().filter(|test| is_failure(test)).collect()
+    }
+
+    // The result of the run on a single line
+    fn line(&self, colour: bool) -> String {
+        format!(
+            "test result: {}. {} passed; {} failed; {} timed out; {} over budget; {} regressed; {} deviate from the baseline; {} ignored; finished in {} μs consuming {} μJ",
+            passed(self.success, colour),
+            self.count(|s| *s == TestResult::Passed),
+            self.count(|s| matches!(s, TestResult::Failed(_))),
+            self.count(|s| *s == TestResult::TimedOut),
+            self.count(|s| *s == TestResult::OverBudget),
+            self.regressions.len(),
+            self.deviations.len(),
+            self.count(|s| *s == TestResult::Ignored),
+            self.total_us,
+            self.total_uj
+        )
+    }
+}
+
+pub(super) fn formatter(format: OutputFormat) -> Box<dyn Formatter> {
+    let colour = use_colour();
+    match format {
+        OutputFormat::Pretty => Box::new(PrettyFormatter::new(io::stdout(), colour)),
+        OutputFormat::Terse => Box::new(TerseFormatter::new(io::stdout(), colour)),
+        OutputFormat::Tap => Box::new(TapFormatter::new(io::stdout())),
+        OutputFormat::Quiet => Box::new(QuietFormatter::new(io::stdout(), colour)),
+        OutputFormat::Json => Box::new(JsonFormatter::new(io::stdout())),
+    }
+}
+
+// Colours are only used on a terminal, unless they are turned off with `NO_COLOR`
+fn use_colour() -> bool {
+    io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+#[derive(Clone, Copy)]
+enum Colour {
+    Green,
+    Red,
+    Yellow,
+}
+
+fn paint(text: &str, colour: Colour, enabled: bool) -> String {
+    if !enabled {
+        return text.to_string();
+    }
+    let code = match colour {
+        Colour::Green => 32,
+        Colour::Red => 31,
+        Colour::Yellow => 33,
+    };
+    format!("\x1b[{code}m{text}\x1b[0m")
+}
+
+fn passed(condition: bool, colour: bool) -> String {
+    if condition {
+        paint("ok", Colour::Green, colour)
+    } else {
+        paint("FAILED", Colour::Red, colour)
+    }
+}
+
+fn is_failure(test: &CompletedTest) -> bool {
+    matches!(
+        test.state,
+        TestResult::Failed(_) | TestResult::TimedOut | TestResult::OverBudget
+    )
+}
+
+// The reason that a test failed, if it did not fail with a message of its own
+fn failure_message(test: &CompletedTest) -> Option<String> {
+    match &test.state {
+        TestResult::Failed(message) => message.clone(),
+        TestResult::TimedOut => Some("test exceeded its timeout".to_string()),
+        TestResult::OverBudget => Some(format!(
+            "test consumed {} μJ per iteration, which exceeds its budget of {} μJ",
+            test.uj_per_iteration().unwrap_or_default(),
+            test.options.budget_uj.unwrap_or_default()
+        )),
+        TestResult::Passed | TestResult::Ignored => None,
+    }
+}
+
+// The captured output and the reason of every test that failed
+fn write_failures(out: &mut impl Write, tests: &[&CompletedTest]) -> io::Result<()> {
+    if tests.is_empty() {
+        return Ok(());
+    }
+    for test in tests {
+        if let Some(captured) = &test.stdout {
+            write!(out, "\n---- {} stdout ----\n", test.name)?;
+            out.write_all(captured)?;
+            out.write_all(b"\n")?;
+        }
+    }
+    out.write_all(b"\nfailures:\n")?;
+    for test in tests {
+        match failure_message(test) {
+            Some(message) => writeln!(out, "\t{}: {message}", test.name)?,
+            None => writeln!(out, "\t{}", test.name)?,
+        }
+    }
+    out.write_all(b"\n")
+}
+
+fn write_regressions(
+    out: &mut impl Write,
+    regressions: &[Regression],
+    history: usize,
+) -> io::Result<()> {
+    if history == 0 {
+        out.write_all(b"\nno previous results to compare with in this environment\n")?;
+    }
+    if regressions.is_empty() {
+        return Ok(());
+    }
+    writeln!(out, "\nregressions compared to the last {history} runs:")?;
+    for regression in regressions {
+        write!(
+            out,
+            "\t{}: {:.0} μJ per iteration, up {:.1}% from {:.0} μJ",
+            regression.name, regression.current_uj, regression.increase, regression.baseline_uj
+        )?;
+        match regression.p_value {
+            Some(p_value) => writeln!(out, " (p = {p_value:.4})")?,
+            None => out.write_all(b"\n")?,
+        }
+    }
+    out.write_all(b"\n")
+}
+
+fn write_deviations(out: &mut impl Write, deviations: &[Pending]) -> io::Result<()> {
+    if deviations.is_empty() {
+        return Ok(());
+    }
+    out.write_all(b"\nbaseline deviations:\n")?;
+    for pending in deviations {
+        write!(out, "\t{}: {} μJ per iteration, ", pending.name, pending.uj)?;
+        match pending.accepted_uj {
+            Some(accepted) => writeln!(out, "accepted {accepted} μJ ± {}%", pending.tolerance)?,
+            None => out.write_all(b"not part of the baseline yet\n")?,
+        }
+    }
+    out.write_all(b"\nrun `cargo coppers accept` to accept the new energy of these tests\n")
+}
+
+// Everything about the run that needs attention, which every formatter for people shows
+fn write_problems(out: &mut impl Write, summary: &Summary) -> io::Result<()> {
+    write_failures(out, &summary.failures())?;
+    if let Some(history) = summary.history {
+        write_regressions(out, summary.regressions, history)?;
+    }
+    write_deviations(out, summary.deviations)
+}
+
+#[cfg(test)]
+fn example_tests() -> Vec<CompletedTest> {
+    use super::Iteration;
+    vec![
+        CompletedTest {
+            state: TestResult::Passed,
+            uj: Some(30),
+            us: Some(1500),
+            counted_repeats: 2,
+            iterations: vec![
+                Iteration {
+                    uj: 15,
+                    us: 750,
+                    ..Default::default()
+                };
+                2
+            ],
+            ..CompletedTest::empty("tests::a".to_string())
+        },
+        CompletedTest {
+            state: TestResult::Failed(Some("assertion failed".to_string())),
+            stdout: Some(b"some output".to_vec()),
+            ..CompletedTest::empty("tests::b".to_string())
+        },
+        CompletedTest::empty("tests::c".to_string()),
+    ]
+}
+
+#[cfg(test)]
+fn example_summary(tests: &[CompletedTest]) -> Summary<'_> {
+    Summary {
+        tests,
+        regressions: &[],
+        history: None,
+        deviations: &[],
+        success: false,
+        total_uj: 100,
+        total_us: 2000,
+        test_uj: 30,
+        test_us: 1500,
+        overhead_uj: 70,
+        overhead_us: 500,
+    }
+}
+
+// Write a run of the example tests
+#[cfg(test)]
+fn write_example(formatter: &mut dyn Formatter) {
+    let tests = example_tests();
+    let names: Vec<&str> = tests.iter().map(|test| test.name.as_str()).collect();
+    formatter.write_run_start(&names).unwrap();
+    for test in &tests {
+        formatter.write_test_start(&test.name).unwrap();
+        formatter.write_result(test).unwrap();
+    }
+    formatter
+        .write_run_finish(&example_summary(&tests))
+        .unwrap();
+}
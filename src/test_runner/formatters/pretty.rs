@@ -0,0 +1,156 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A line with the energy of every test, followed by the failures and a summary of the run. The
+// columns of the lines are aligned, so the energy of the tests is easy to compare.
+
+use super::{paint, passed, write_problems, Colour, Formatter, Summary};
+use crate::test_runner::{CompletedTest, TestResult};
+use std::io::{self, Write};
+use std::time::Duration;
+
+// Width of the longest state, `OVER BUDGET`
+const STATE_WIDTH: usize = 11;
+
+pub(super) struct PrettyFormatter<W> {
+    out: W,
+    colour: bool,
+    // Width of the longest name of a test
+    name_width: usize,
+}
+
+impl<W: Write> PrettyFormatter<W> {
+    pub(super) fn new(out: W, colour: bool) -> Self {
+        PrettyFormatter {
+            out,
+            colour,
+            name_width: 0,
+        }
+    }
+}
+
+impl<W: Write> Formatter for PrettyFormatter<W> {
+    fn write_run_start(&mut self, tests: &[&str]) -> io::Result<()> {
+        self.name_width = tests.iter().map(|name| name.len()).max().unwrap_or(0);
+        writeln!(self.out, "Running {} tests", tests.len())
+    }
+
+    fn write_message(&mut self, message: &str) -> io::Result<()> {
+        writeln!(self.out, "{message}")
+    }
+
+    fn write_test_start(&mut self, _name: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_long_running(&mut self, name: &str, elapsed: Duration) -> io::Result<()> {
+        writeln!(
+            self.out,
+            "test {name} has been running for over {} seconds",
+            elapsed.as_secs()
+        )
+    }
+
+    fn write_result(&mut self, test: &CompletedTest) -> io::Result<()> {
+        let (state, colour) = match test.state {
+            TestResult::Passed => ("ok", Colour::Green),
+            TestResult::Failed(_) => ("FAILED", Colour::Red),
+            TestResult::TimedOut => ("TIMEOUT", Colour::Red),
+            TestResult::OverBudget => ("OVER BUDGET", Colour::Yellow),
+            TestResult::Ignored => ("ignored", Colour::Yellow),
+        };
+        // The state is padded before it is coloured, as the colour codes do not take up space
+        let state = paint(&format!("{state:<STATE_WIDTH$}"), colour, self.colour);
+        let name = format!("{:<width$}", test.name, width = self.name_width);
+        let (Some(uj), Some(us)) = (test.uj, test.us) else {
+            return writeln!(self.out, "test {name} ... {}", state.trim_end());
+        };
+        let average = |value: Option<f64>| value.map_or("-".to_string(), |v| format!("{v:.1}"));
+        let outliers = match test.outliers {
+            0 => String::new(),
+            1 => ", 1 outlier".to_string(),
+            n => format!(", {n} outliers"),
+        };
+        writeln!(
+            self.out,
+            "test {name} ... {state} {uj:>10} μJ {us:>10} μs {:>4} iterations {:>10} μJ {:>10} μs per iteration{outliers}",
+            test.counted_repeats,
+            average(test.average_uj()),
+            average(test.average_us())
+        )?;
+        for region in &test.regions {
+            writeln!(
+                self.out,
+                "    region {} - [{} μJ in {} μs over {} calls]",
+                region.name, region.uj, region.us, region.calls
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_run_finish(&mut self, summary: &Summary) -> io::Result<()> {
+        write_problems(&mut self.out, summary)?;
+
+        let Summary {
+            total_uj,
+            total_us,
+            test_uj,
+            test_us,
+            overhead_uj,
+            overhead_us,
+            ..
+        } = summary;
+        let passed_tests = summary.count(|s| *s == TestResult::Passed);
+        let failed = summary.count(|s| matches!(s, TestResult::Failed(_)));
+        let timed_out = summary.count(|s| *s == TestResult::TimedOut);
+        let over_budget = summary.count(|s| *s == TestResult::OverBudget);
+        let ignored = summary.count(|s| *s == TestResult::Ignored);
+        writeln!(self.out, "test result: {}.\n\t{passed_tests} passed;\n\t{failed} failed;\n\t{timed_out} timed out;\n\t{over_budget} over budget;\n\t{} regressed;\n\t{} deviate from the baseline;\n\t{ignored} ignored;\n\tfinished in {total_us} μs consuming {total_uj} μJ\n\tspend {test_us} μs and {test_uj} μJ on tests\n\tspend {overhead_us} μs and {overhead_uj} μJ on overhead", passed(summary.success, self.colour), summary.regressions.len(), summary.deviations.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::write_example;
+    use super::*;
+
+    #[test]
+    fn aligned_lines() {
+        let mut output = Vec::new();
+        write_example(&mut PrettyFormatter::new(&mut output, false));
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "Running 3 tests");
+        assert_eq!(
+            lines[1],
+            "test tests::a ... ok                  30 μJ       1500 μs    2 iterations       15.0 μJ      750.0 μs per iteration"
+        );
+        assert_eq!(lines[2], "test tests::b ... FAILED");
+        assert_eq!(lines[3], "test tests::c ... ignored");
+        assert!(output.contains("\nfailures:\n\ttests::b: assertion failed\n"));
+        assert!(output.contains("test result: FAILED.\n\t1 passed;\n\t1 failed;"));
+    }
+
+    #[test]
+    fn coloured_state() {
+        let mut output = Vec::new();
+        let mut formatter = PrettyFormatter::new(&mut output, true);
+        formatter.write_run_start(&["tests::c"]).unwrap();
+        formatter
+            .write_result(&CompletedTest::empty("tests::c".to_string()))
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with("test tests::c ... \x1b[33mignored    \x1b[0m\n"));
+    }
+}
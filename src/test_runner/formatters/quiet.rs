@@ -0,0 +1,92 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Only what needs attention: the failures, regressions and deviations from the baseline, and the
+// result of the run if it failed. Nothing is written for a run in which every test passed.
+
+use super::{write_problems, Formatter, Summary};
+use crate::test_runner::CompletedTest;
+use std::io::{self, Write};
+use std::time::Duration;
+
+pub(super) struct QuietFormatter<W> {
+    out: W,
+    colour: bool,
+}
+
+impl<W: Write> QuietFormatter<W> {
+    pub(super) fn new(out: W, colour: bool) -> Self {
+        QuietFormatter { out, colour }
+    }
+}
+
+impl<W: Write> Formatter for QuietFormatter<W> {
+    fn write_run_start(&mut self, _tests: &[&str]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_message(&mut self, message: &str) -> io::Result<()> {
+        writeln!(io::stderr(), "{message}")
+    }
+
+    fn write_test_start(&mut self, _name: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_long_running(&mut self, name: &str, elapsed: Duration) -> io::Result<()> {
+        writeln!(
+            io::stderr(),
+            "test {name} has been running for over {} seconds",
+            elapsed.as_secs()
+        )
+    }
+
+    fn write_result(&mut self, _test: &CompletedTest) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_run_finish(&mut self, summary: &Summary) -> io::Result<()> {
+        write_problems(&mut self.out, summary)?;
+        if !summary.success {
+            writeln!(self.out, "{}", summary.line(self.colour))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{example_summary, example_tests, write_example};
+    use super::*;
+
+    #[test]
+    fn only_problems() {
+        let mut output = Vec::new();
+        write_example(&mut QuietFormatter::new(&mut output, false));
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("\n---- tests::b stdout ----\nsome output\n"));
+        assert!(output.ends_with("1 ignored; finished in 2000 μs consuming 100 μJ\n"));
+
+        let mut output = Vec::new();
+        let tests = example_tests().into_iter().take(1).collect::<Vec<_>>();
+        let summary = Summary {
+            success: true,
+            ..example_summary(&tests)
+        };
+        QuietFormatter::new(&mut output, false)
+            .write_run_finish(&summary)
+            .unwrap();
+        assert!(output.is_empty());
+    }
+}
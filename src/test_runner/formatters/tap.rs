@@ -0,0 +1,154 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The Test Anything Protocol, version 13. The energy of every test is part of the YAML block
+// below its test point, and everything else is written as a comment, so TAP consumers only see
+// the tests.
+
+use super::{failure_message, is_failure, write_problems, Formatter, Summary};
+use crate::test_runner::{CompletedTest, TestResult};
+use std::io::{self, Write};
+use std::time::Duration;
+
+pub(super) struct TapFormatter<W> {
+    out: W,
+    // The number of the last test point
+    number: usize,
+}
+
+impl<W: Write> TapFormatter<W> {
+    pub(super) fn new(out: W) -> Self {
+        TapFormatter { out, number: 0 }
+    }
+
+    fn write_comment(&mut self, text: &str) -> io::Result<()> {
+        for line in text.lines() {
+            if line.is_empty() {
+                writeln!(self.out, "#")?;
+            } else {
+                writeln!(self.out, "# {line}")?;
+            }
+        }
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Formatter for TapFormatter<W> {
+    fn write_run_start(&mut self, tests: &[&str]) -> io::Result<()> {
+        self.number = 0;
+        writeln!(self.out, "TAP version 13\n1..{}", tests.len())
+    }
+
+    fn write_message(&mut self, message: &str) -> io::Result<()> {
+        self.write_comment(message)
+    }
+
+    fn write_test_start(&mut self, _name: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_long_running(&mut self, name: &str, elapsed: Duration) -> io::Result<()> {
+        self.write_comment(&format!(
+            "test {name} has been running for over {} seconds",
+            elapsed.as_secs()
+        ))
+    }
+
+    fn write_result(&mut self, test: &CompletedTest) -> io::Result<()> {
+        self.number += 1;
+        if test.state == TestResult::Ignored {
+            writeln!(
+                self.out,
+                "ok {} - {} # SKIP ignored",
+                self.number, test.name
+            )?;
+            return self.out.flush();
+        }
+        let status = if is_failure(test) { "not ok" } else { "ok" };
+        writeln!(self.out, "{status} {} - {}", self.number, test.name)?;
+
+        // Strings are quoted as JSON, which is valid YAML
+        let quote = |text: &str| serde_json::to_string(text).unwrap();
+        let mut lines = Vec::new();
+        if is_failure(test) {
+            let state = match test.state {
+                TestResult::Failed(_) => "failed",
+                TestResult::TimedOut => "timed out",
+                _ => "over budget",
+            };
+            lines.push(format!("state: {state}"));
+            if let Some(message) = failure_message(test) {
+                lines.push(format!("message: {}", quote(&message)));
+            }
+            if let Some(captured) = &test.stdout {
+                lines.push(format!(
+                    "output: {}",
+                    quote(&String::from_utf8_lossy(captured))
+                ));
+            }
+        }
+        if let Some(uj) = test.uj {
+            lines.push(format!("energy_uj: {uj}"));
+        }
+        if let Some(us) = test.us {
+            lines.push(format!("time_us: {us}"));
+        }
+        lines.push(format!("iterations: {}", test.counted_repeats));
+        lines.push(format!("outliers: {}", test.outliers));
+        if let Some(uj) = test.average_uj() {
+            lines.push(format!("energy_uj_per_iteration: {uj}"));
+        }
+        if let Some(us) = test.average_us() {
+            lines.push(format!("time_us_per_iteration: {us}"));
+        }
+        if let Some(budget) = test.options.budget_uj {
+            lines.push(format!("budget_uj: {budget}"));
+        }
+        writeln!(self.out, "  ---")?;
+        for line in lines {
+            writeln!(self.out, "  {line}")?;
+        }
+        writeln!(self.out, "  ...")?;
+        self.out.flush()
+    }
+
+    fn write_run_finish(&mut self, summary: &Summary) -> io::Result<()> {
+        let mut problems = Vec::new();
+        write_problems(&mut problems, summary)?;
+        self.write_comment(String::from_utf8_lossy(&problems).trim())?;
+        self.write_comment(&summary.line(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::write_example;
+    use super::*;
+
+    #[test]
+    fn test_points() {
+        let mut output = Vec::new();
+        write_example(&mut TapFormatter::new(&mut output));
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(
+            "TAP version 13\n1..3\nok 1 - tests::a\n  ---\n  energy_uj: 30\n  time_us: 1500\n  iterations: 2\n  outliers: 0\n  energy_uj_per_iteration: 15\n  time_us_per_iteration: 750\n  ...\n"
+        ));
+        assert!(output.contains(
+            "not ok 2 - tests::b\n  ---\n  state: failed\n  message: \"assertion failed\"\n  output: \"some output\"\n  iterations: 0\n  outliers: 0\n  ...\n"
+        ));
+        assert!(output.contains("ok 3 - tests::c # SKIP ignored\n"));
+        assert!(output.contains("# failures:\n# \ttests::b: assertion failed\n"));
+        assert!(output.ends_with("# test result: FAILED. 1 passed; 1 failed; 0 timed out; 0 over budget; 0 regressed; 0 deviate from the baseline; 1 ignored; finished in 2000 μs consuming 100 μJ\n"));
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A character for every test, like `--format terse` of libtest, followed by the failures and the
+// result of the run on a single line.
+
+use super::{paint, write_problems, Colour, Formatter, Summary};
+use crate::test_runner::{CompletedTest, TestResult};
+use std::io::{self, Write};
+use std::time::Duration;
+
+// The amount of tests after which the progress continues on the next line
+const LINE_WIDTH: usize = 88;
+
+pub(super) struct TerseFormatter<W> {
+    out: W,
+    colour: bool,
+    total: usize,
+    done: usize,
+}
+
+impl<W: Write> TerseFormatter<W> {
+    pub(super) fn new(out: W, colour: bool) -> Self {
+        TerseFormatter {
+            out,
+            colour,
+            total: 0,
+            done: 0,
+        }
+    }
+
+    // Messages are written on a line of their own, so they do not end up between the progress
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if !self.done.is_multiple_of(LINE_WIDTH) {
+            self.out.write_all(b"\n")?;
+        }
+        writeln!(self.out, "{line}")?;
+        if !self.done.is_multiple_of(LINE_WIDTH) {
+            // Continue the progress on a new line with the same width
+            write!(self.out, "{:width$}", "", width = self.done % LINE_WIDTH)?;
+        }
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Formatter for TerseFormatter<W> {
+    fn write_run_start(&mut self, tests: &[&str]) -> io::Result<()> {
+        self.total = tests.len();
+        self.done = 0;
+        writeln!(self.out, "Running {} tests", tests.len())
+    }
+
+    fn write_message(&mut self, message: &str) -> io::Result<()> {
+        self.write_line(message)
+    }
+
+    fn write_test_start(&mut self, _name: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_long_running(&mut self, name: &str, elapsed: Duration) -> io::Result<()> {
+        self.write_line(&format!(
+            "test {name} has been running for over {} seconds",
+            elapsed.as_secs()
+        ))
+    }
+
+    fn write_result(&mut self, test: &CompletedTest) -> io::Result<()> {
+        let (progress, colour) = match test.state {
+            TestResult::Passed => (".", Colour::Green),
+            TestResult::Failed(_) => ("F", Colour::Red),
+            TestResult::TimedOut => ("T", Colour::Red),
+            TestResult::OverBudget => ("B", Colour::Yellow),
+            TestResult::Ignored => ("i", Colour::Yellow),
+        };
+        write!(self.out, "{}", paint(progress, colour, self.colour))?;
+        self.done += 1;
+        if self.done.is_multiple_of(LINE_WIDTH) {
+            writeln!(self.out, " {}/{}", self.done, self.total)?;
+        }
+        self.out.flush()
+    }
+
+    fn write_run_finish(&mut self, summary: &Summary) -> io::Result<()> {
+        if !self.done.is_multiple_of(LINE_WIDTH) {
+            self.out.write_all(b"\n")?;
+        }
+        write_problems(&mut self.out, summary)?;
+        writeln!(self.out, "\n{}", summary.line(self.colour))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::write_example;
+    use super::*;
+
+    #[test]
+    fn progress_characters() {
+        let mut output = Vec::new();
+        write_example(&mut TerseFormatter::new(&mut output, false));
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Running 3 tests\n.Fi\n"));
+        assert!(output.contains("\nfailures:\n\ttests::b: assertion failed\n"));
+        assert!(output.ends_with(
+            "test result: FAILED. 1 passed; 1 failed; 0 timed out; 0 over budget; 0 regressed; 0 deviate from the baseline; 1 ignored; finished in 2000 μs consuming 100 μJ\n"
+        ));
+    }
+
+    #[test]
+    fn wraps_long_runs() {
+        let mut output = Vec::new();
+        let mut formatter = TerseFormatter::new(&mut output, false);
+        let names = vec!["test"; LINE_WIDTH + 1];
+        formatter.write_run_start(&names).unwrap();
+        for _ in 0..=LINE_WIDTH {
+            formatter
+                .write_result(&CompletedTest {
+                    state: TestResult::Passed,
+                    ..CompletedTest::empty("test".to_string())
+                })
+                .unwrap();
+        }
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[1], format!("{} 88/89", ".".repeat(LINE_WIDTH)));
+        assert_eq!(lines[2], ".");
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The state of the git repository that the tested crate is part of. The crate does not have to
+// be in the root of the repository, or in a repository at all, and the repository might not
+// have any commits yet.
+
+use git2::{DiffFormat, DiffOptions, ObjectType, Oid, Repository};
+use std::path::Path;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct GitInfo {
+    // The commit that is checked out, unless the repository has no commits yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_timestamp: Option<i64>,
+    // The branch that is checked out, unless the HEAD is detached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    // Whether the working tree has changes that are not committed, including untracked files
+    pub dirty: bool,
+    // Hash of the uncommitted changes, so runs with the same changes can be recognized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff_hash: Option<String>,
+}
+
+impl GitInfo {
+    // The state of the repository that contains the directory, searching upwards from it.
+    // Returns `None` if the directory is not part of a repository.
+    pub(super) fn of(directory: &Path) -> Option<GitInfo> {
+        let repository = Repository::discover(directory).ok()?;
+        let commit = repository
+            .head()
+            .ok()
+            .and_then(|head| head.peel_to_commit().ok());
+        let changes = commit
+            .as_ref()
+            .map(|commit| commit.tree().ok())
+            .unwrap_or_default();
+        let changes = uncommitted_changes(&repository, changes.as_ref());
+        Some(GitInfo {
+            head: commit.as_ref().map(|commit| commit.id().to_string()),
+            commit_timestamp: commit.as_ref().map(|commit| commit.time().seconds()),
+            branch: branch(&repository),
+            dirty: changes.is_some(),
+            diff_hash: changes.map(|patch| {
+                Oid::hash_object(ObjectType::Blob, &patch)
+                    .unwrap()
+                    .to_string()
+            }),
+        })
+    }
+}
+
+// The HEAD of a branch without commits refers to that branch as well, while a detached HEAD
+// refers to a commit directly
+fn branch(repository: &Repository) -> Option<String> {
+    let head = repository.find_reference("HEAD").ok()?;
+    head.symbolic_target()?
+        .strip_prefix("refs/heads/")
+        .map(str::to_string)
+}
+
+// The patch of all changes compared to the commit, or `None` if there are no changes. A bare
+// repository does not have a working tree, so it does not have changes either.
+fn uncommitted_changes(repository: &Repository, tree: Option<&git2::Tree>) -> Option<Vec<u8>> {
+    let mut options = DiffOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true);
+    let diff = repository
+        .diff_tree_to_workdir_with_index(tree, Some(&mut options))
+        .ok()?;
+    if diff.deltas().len() == 0 {
+        return None;
+    }
+    let mut patch = Vec::new();
+    let _ = diff.print(DiffFormat::Patch, |_, _, line| {
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            patch.push(line.origin() as u8);
+        }
+        patch.extend_from_slice(line.content());
+        true
+    });
+    Some(patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::path::PathBuf;
+
+    fn empty_directory(name: &str) -> PathBuf {
+        let directory = temp_dir().join(format!("coppers-git-{name}-{}", std::process::id()));
+        let _ = remove_dir_all(&directory);
+        create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn commit(repository: &Repository) {
+        let mut index = repository.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let tree = repository.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Coppers", "coppers@example.com").unwrap();
+        let parent = repository.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        repository
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                "Commit",
+                &tree,
+                &parents,
+            )
+            .unwrap();
+        index.write().unwrap();
+    }
+
+    #[test]
+    fn outside_of_a_repository() {
+        let directory = empty_directory("none");
+        // The temporary directory itself is not expected to be part of a repository
+        if Repository::discover(&directory).is_err() {
+            assert_eq!(GitInfo::of(&directory), None);
+        }
+        remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn repository_without_commits() {
+        let directory = empty_directory("unborn");
+        Repository::init(&directory).unwrap();
+        let info = GitInfo::of(&directory).unwrap();
+        assert_eq!(info.head, None);
+        assert!(info.branch.is_some());
+        assert!(!info.dirty);
+        remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn dirty_repository_from_subdirectory() {
+        let directory = empty_directory("dirty");
+        let repository = Repository::init(&directory).unwrap();
+        create_dir_all(directory.join("crate")).unwrap();
+        write(directory.join("crate/lib.rs"), "fn a() {}\n").unwrap();
+        commit(&repository);
+
+        let clean = GitInfo::of(&directory.join("crate")).unwrap();
+        assert!(clean.head.is_some());
+        assert!(!clean.dirty);
+        assert_eq!(clean.diff_hash, None);
+
+        write(directory.join("crate/lib.rs"), "fn b() {}\n").unwrap();
+        let dirty = GitInfo::of(&directory.join("crate")).unwrap();
+        assert!(dirty.dirty);
+        assert_eq!(dirty.head, clean.head);
+        write(directory.join("crate/lib.rs"), "fn c() {}\n").unwrap();
+        let other = GitInfo::of(&directory.join("crate")).unwrap();
+        assert_ne!(other.diff_hash, dirty.diff_hash);
+
+        // A detached HEAD is not on a branch
+        repository
+            .set_head_detached(Oid::from_str(clean.head.as_ref().unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(GitInfo::of(&directory).unwrap().branch, None);
+        remove_dir_all(directory).unwrap();
+    }
+}
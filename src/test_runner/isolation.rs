@@ -0,0 +1,312 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Running a test in isolation means that the runner starts the current test binary again, but
+// with an argument that tells the runner in that process to run only a single test. The child
+// process reports the result of that test back over its standard output.
+
+use super::{
+    aggregate, run_test, warn_long_running, CompletedTest, Iteration, TestDefinition, TestOptions,
+    TestResult, TEST_WARN_TIMEOUT,
+};
+use crate::config::{Config, OutputFormat};
+use crate::registry::ShouldPanic;
+use crate::sensors::default_sensor;
+use std::env;
+use std::io::{ErrorKind, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Prefix of the line on which a child process reports the result of its test
+const RESULT_PREFIX: &str = "coppers-result:";
+
+// The child process enforces the timeout of a test itself, so it can still report the
+// iterations that completed. It is only killed if it does not manage to do so in time.
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+// Run a single test in this process, and report the result to the runner that started it
+pub(super) fn run_child(tests: Vec<TestDefinition>, name: &str, config: &Config) {
+    let test = tests
+        .into_iter()
+        .find(|test| test.name == name)
+        .unwrap_or_else(|| panic!("There is no test with the name `{name}`"));
+    let result = run_test(test, config);
+    println!("{RESULT_PREFIX}{}", serde_json::to_string(&result).unwrap());
+}
+
+// Run a single test in a new process
+pub(super) fn run_in_process(
+    test: &TestDefinition,
+    options: TestOptions,
+    config: &Config,
+) -> CompletedTest {
+    let name = test.name.clone();
+
+    let mut sensor = default_sensor();
+    let start = Instant::now();
+    sensor.start_measuring();
+
+    // Pass along the original arguments, so the child process uses the same configuration
+    let mut child = Command::new(env::current_exe().unwrap())
+        .args(env::args().skip(1))
+        .arg("--coppers-run-test")
+        .arg(&name)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = read_in_background(child.stdout.take().unwrap());
+    let stderr = read_in_background(child.stderr.take().unwrap());
+
+    let timeout = config
+        .timeout_of(&name)
+        .map(|timeout| timeout + TIMEOUT_GRACE_PERIOD);
+    let status = wait(child, &name, timeout, config.format);
+
+    sensor.stop_measuring();
+    let process_uj = sensor.measures_energy().then(|| sensor.get_measured_uj());
+    let process_us = sensor.get_elapsed_time_us();
+
+    // Everything that the test did not capture itself, like the message of an abort, is kept
+    // as output of the test as well.
+    let mut result = None;
+    let mut output = Vec::new();
+    for line in stdout.join().unwrap().split_inclusive(|b| *b == b'\n') {
+        match line.strip_prefix(RESULT_PREFIX.as_bytes()) {
+            Some(json) => result = serde_json::from_slice::<CompletedTest>(json).ok(),
+            None => output.extend_from_slice(line),
+        }
+    }
+    output.extend(stderr.join().unwrap());
+
+    let mut result = result.unwrap_or_else(|| {
+        // The process did not get to report the result, so all that is known is the energy
+        // that the process consumed as a whole
+        let state = match status {
+            Some(status) => exit_state(test.should_panic, status),
+            None => TestResult::TimedOut,
+        };
+        let mut iterations = vec![Iteration {
+            uj: process_uj.unwrap_or(0),
+            us: process_us,
+            ..Default::default()
+        }];
+        let (uj, us, counted_repeats, outliers) = aggregate(&mut iterations, &config.outliers);
+        CompletedTest {
+            state,
+            uj: process_uj.map(|_| uj),
+            us: Some(us),
+            counted_repeats,
+            outliers,
+            iterations,
+            options,
+            ..CompletedTest::empty(name)
+        }
+    });
+
+    if config.isolation.include_startup {
+        let (iterations_uj, iterations_us) = result.consumed();
+        result.uj = result
+            .uj
+            .zip(process_uj)
+            .map(|(uj, process_uj)| uj + process_uj.saturating_sub(iterations_uj));
+        result.us = result
+            .us
+            .map(|us| us + process_us.saturating_sub(iterations_us));
+    }
+    result.process_uj = process_uj;
+    result.process_us = Some(process_us);
+    result.window = Some((start, Instant::now()));
+
+    if matches!(result.state, TestResult::Failed(_) | TestResult::TimedOut) {
+        result.stdout.get_or_insert_with(Vec::new).extend(output);
+    }
+    result
+}
+
+// Wait until the process of a test exits. Returns `None` if the process was killed because it
+// exceeded its timeout. Another thread waits until the process exits without reaping it, so the
+// runner does not wake up while the process is measured, other than to warn about it or to kill
+// it. The runner keeps ownership of the process, so it can only be killed before it is reaped.
+fn wait(
+    child: Child,
+    name: &str,
+    timeout: Option<Duration>,
+    format: OutputFormat,
+) -> Option<ExitStatus> {
+    let child = Arc::new(Mutex::new(child));
+    let (sender, receiver) = mpsc::channel();
+    let waiting = Arc::clone(&child);
+    thread::spawn(move || {
+        wait_for_exit(&waiting);
+        let _ = sender.send(());
+    });
+
+    let start = Instant::now();
+    let mut warned = false;
+    loop {
+        // Wake up when the test should be warned about or when it times out
+        let wait_until = match (timeout, warned) {
+            (Some(timeout), false) => Some(timeout.min(TEST_WARN_TIMEOUT)),
+            (Some(timeout), true) => Some(timeout),
+            (None, false) => Some(TEST_WARN_TIMEOUT),
+            (None, true) => None,
+        };
+        let exited = match wait_until {
+            Some(wait_until) => receiver.recv_timeout(wait_until.saturating_sub(start.elapsed())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match exited {
+            Ok(()) => return Some(child.lock().unwrap().wait().unwrap()),
+            Err(RecvTimeoutError::Timeout) => {
+                if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                    // The process might have exited in the meantime, but it is not reaped yet
+                    let mut child = child.lock().unwrap();
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return None;
+                }
+                if !warned {
+                    warned = warn_long_running(name, start.elapsed(), format);
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                panic!("Could not wait for the process of `{name}`")
+            }
+        }
+    }
+}
+
+// Block until the process exits, but leave it to be reaped by its owner
+#[cfg(unix)]
+fn wait_for_exit(child: &Mutex<Child>) {
+    let id = child.lock().unwrap().id();
+    loop {
+        // SAFETY: `info` is valid for writes, and `WNOWAIT` leaves the process in a waitable
+        // state, so its ID cannot be reused until the owner of `child` reaps it
+        let result = unsafe {
+            let mut info: libc::siginfo_t = std::mem::zeroed();
+            libc::waitid(
+                libc::P_PID,
+                id as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if result == 0 || std::io::Error::last_os_error().kind() != ErrorKind::Interrupted {
+            return;
+        }
+    }
+}
+
+// Without a way to wait for a process without reaping it, the process is checked regularly
+#[cfg(not(unix))]
+fn wait_for_exit(child: &Mutex<Child>) {
+    while let Ok(None) = child.lock().unwrap().try_wait() {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = pipe.read_to_end(&mut buffer);
+        buffer
+    })
+}
+
+// A test that is compiled with `panic = "abort"` aborts the whole process when it panics,
+// which is the expected outcome of a test that should panic. The panic message is lost with
+// the process, so a test that expects a message fails, because the message cannot be checked.
+fn exit_state(should_panic: ShouldPanic, status: ExitStatus) -> TestResult {
+    match should_panic {
+        ShouldPanic::Yes if aborted(&status) => TestResult::Passed,
+        ShouldPanic::YesWithMessage(expected) if aborted(&status) => {
+            TestResult::Failed(Some(format!(
+                "the process of the test aborted, so its panic message could not be checked against the expected message `{expected}`"
+            )))
+        }
+        _ => TestResult::Failed(Some(format!(
+            "the process of the test stopped unexpectedly ({status})"
+        ))),
+    }
+}
+
+#[cfg(unix)]
+fn aborted(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    // SIGABRT
+    status.signal() == Some(6)
+}
+
+#[cfg(not(unix))]
+fn aborted(_status: &ExitStatus) -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn aborted_process() {
+        let aborted = ExitStatus::from_raw(libc::SIGABRT);
+        assert_eq!(exit_state(ShouldPanic::Yes, aborted), TestResult::Passed);
+        assert!(matches!(
+            exit_state(ShouldPanic::YesWithMessage("overflow"), aborted),
+            TestResult::Failed(Some(message)) if message.contains("`overflow`")
+        ));
+        assert!(matches!(
+            exit_state(ShouldPanic::No, aborted),
+            TestResult::Failed(Some(_))
+        ));
+    }
+
+    #[test]
+    fn wait_kills_process_after_timeout() {
+        let child = Command::new("sleep").arg("10").spawn().unwrap();
+        let start = Instant::now();
+        let status = wait(
+            child,
+            "sleep",
+            Some(Duration::from_millis(50)),
+            OutputFormat::Pretty,
+        );
+        assert_eq!(status, None);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let child = Command::new("true").spawn().unwrap();
+        let status = wait(
+            child,
+            "true",
+            Some(Duration::from_secs(10)),
+            OutputFormat::Pretty,
+        );
+        assert!(status.unwrap().success());
+    }
+
+    #[test]
+    fn process_that_exited() {
+        let exited = ExitStatus::from_raw(1 << 8);
+        assert!(matches!(
+            exit_state(ShouldPanic::Yes, exited),
+            TestResult::Failed(Some(_))
+        ));
+    }
+}
@@ -1,64 +1,261 @@
-use git2::Repository;
-use std::env::current_dir;
-use std::fs::{create_dir_all, File};
+use std::env::current_exe;
+use std::fs::{create_dir_all, write, File};
 use std::io::Write;
+use std::path::Path;
 use std::time::SystemTime;
 
+use super::environment::Environment;
+use super::git::GitInfo;
+use super::regression::Regression;
+use super::run_id::{append_to_index, invocation_id, new_run_id, IndexEntry};
+use super::schema::{upgrade, SCHEMA_VERSION};
 use super::CompletedTest;
-use super::REPEAT_TESTS_AMOUNT_OF_TIMES;
-
-#[derive(serde::Serialize)]
-struct JsonResult {
-    execution_timestamp: u64,
-    head: String,
-    commit_timestamp: i64,
-    total_us: u128,
-    total_uj: u128,
-    overhead_us: u128,
-    overhead_uj: u128,
-    number_of_repeats: usize,
-    tests: Vec<CompletedTest>,
+use crate::baseline::Pending;
+use crate::config::{crate_root, Config, OutlierConfig};
+
+// When this environment variable is set, the runner writes its result to the file it points to
+// instead of to the results directory
+pub(crate) const RESULT_FILE_VARIABLE: &str = "COPPERS_RESULT_FILE";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct JsonResult {
+    // Version of the format, which is described by `schema/coppers_results.schema.json`
+    #[serde(default)]
+    pub(super) schema_version: u64,
+    // Unique ID of this run
+    #[serde(default)]
+    pub(super) run_id: String,
+    // Shared by the runs of the test binaries that are started by the same process
+    #[serde(default)]
+    pub(super) invocation_id: String,
+    // The runs that are merged into this run by `cargo coppers test`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) merged_runs: Vec<String>,
+    pub(super) execution_timestamp: u64,
+    // The machine and toolchain that the run is executed with
+    #[serde(default)]
+    pub(super) environment: Environment,
+    // The state of the repository that the crate is part of, unless it is not part of one or
+    // the git information is omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) git: Option<GitInfo>,
+    pub(super) total_us: u128,
+    pub(super) total_uj: u128,
+    pub(super) overhead_us: u128,
+    pub(super) overhead_uj: u128,
+    #[serde(default)]
+    pub(super) test_threads: usize,
+    #[serde(default)]
+    pub(super) outlier_detection: OutlierConfig,
+    pub(super) tests: Vec<CompletedTest>,
+    // Tests that consume significantly more energy than in the previous runs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) regressions: Vec<Regression>,
+    // Tests that deviate from the baseline that is checked into the repository
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) baseline_deviations: Vec<Pending>,
+}
+
+impl JsonResult {
+    // A result of the current run, of which the totals still need to be filled in
+    pub(super) fn new(tests: Vec<CompletedTest>, config: &Config) -> JsonResult {
+        // The repository is searched for from the root of the crate, which does not have to be
+        // the root of the repository
+        let git = if config.omit_git {
+            None
+        } else {
+            let root = crate_root();
+            GitInfo::of(if root.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                &root
+            })
+        };
+
+        // Get the timestamp of the current time
+        let execution_timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let binary = binary_name().unwrap_or_else(|| "tests".to_string());
+        JsonResult {
+            schema_version: SCHEMA_VERSION,
+            run_id: new_run_id(&binary),
+            invocation_id: invocation_id(),
+            merged_runs: Vec::new(),
+            execution_timestamp,
+            environment: Environment::current(),
+            git,
+            total_us: 0,
+            total_uj: 0,
+            overhead_us: 0,
+            overhead_uj: 0,
+            test_threads: config.test_threads(),
+            outlier_detection: config.outliers,
+            tests,
+            regressions: Vec::new(),
+            baseline_deviations: Vec::new(),
+        }
+    }
+
+    // Read a result in any version of the format, which is upgraded to the current version
+    pub(crate) fn from_json(contents: &str) -> Result<JsonResult, String> {
+        let result = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+        serde_json::from_value(upgrade(result)?).map_err(|e| e.to_string())
+    }
+
+    // Combine the results of several test binaries of the same invocation into a single run,
+    // which finished when the last of the test binaries finished
+    pub(crate) fn merge(results: Vec<JsonResult>) -> Option<JsonResult> {
+        let mut results = results.into_iter();
+        let mut merged = results.next()?;
+        merged.merged_runs = vec![merged.run_id.clone()];
+        merged.run_id = new_run_id("merged");
+        for result in results {
+            merged.merged_runs.push(result.run_id);
+            merged.execution_timestamp = merged.execution_timestamp.max(result.execution_timestamp);
+            merged.total_us += result.total_us;
+            merged.total_uj += result.total_uj;
+            merged.overhead_us += result.overhead_us;
+            merged.overhead_uj += result.overhead_uj;
+            merged.test_threads = merged.test_threads.max(result.test_threads);
+            merged.tests.extend(result.tests);
+            merged.regressions.extend(result.regressions);
+            merged
+                .baseline_deviations
+                .extend(result.baseline_deviations);
+        }
+        Some(merged)
+    }
 }
 
-pub(crate) fn write_to_json(
-    tests: Vec<CompletedTest>,
-    total_us: u128,
-    total_uj: u128,
-    overhead_us: u128,
-    overhead_uj: u128,
-) {
-    // Get git hash of last commit
-    let current_directory = current_dir().unwrap();
-    let current_path = current_directory.as_path().to_str().unwrap();
-    let repo = Repository::open(current_path).unwrap();
-    let git_head = repo.head().unwrap();
-    let head = hex::encode(git_head.target().unwrap().as_bytes());
-    let commit_timestamp = git_head.peel_to_commit().unwrap().time().seconds();
-
-    // Get the timestamp of the current time
-    let execution_timestamp = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
-    let output = JsonResult {
-        execution_timestamp,
-        head,
-        commit_timestamp,
-        total_us,
-        total_uj,
-        overhead_us,
-        overhead_uj,
-        number_of_repeats: REPEAT_TESTS_AMOUNT_OF_TIMES,
-        tests,
-    };
+// Read access to the results of a run, see `coppers::history`
+impl JsonResult {
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
 
+    pub fn invocation_id(&self) -> &str {
+        &self.invocation_id
+    }
+
+    // Seconds since the epoch at which the run finished
+    pub fn execution_timestamp(&self) -> u64 {
+        self.execution_timestamp
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    // The state of the repository, unless the crate is not part of one or it is omitted
+    pub fn git(&self) -> Option<&GitInfo> {
+        self.git.as_ref()
+    }
+
+    pub fn tests(&self) -> &[CompletedTest] {
+        &self.tests
+    }
+
+    pub fn total_uj(&self) -> u128 {
+        self.total_uj
+    }
+
+    pub fn total_us(&self) -> u128 {
+        self.total_us
+    }
+}
+
+pub(super) fn write_to_json(output: &JsonResult, directory: &Path) {
     // Convert test results in JSON object
-    let output_json = serde_json::to_string(&output).unwrap();
+    let output_json = serde_json::to_string(output).unwrap();
+
+    create_dir_all(directory).unwrap();
+    let json_file_name = format!("coppers_results-{}.json", output.run_id);
+    let mut file = File::create(directory.join(&json_file_name)).unwrap();
+    file.write_all(output_json.as_bytes()).unwrap();
+
+    append_to_index(
+        directory,
+        &IndexEntry {
+            run_id: output.run_id.clone(),
+            invocation_id: output.invocation_id.clone(),
+            execution_timestamp: output.execution_timestamp,
+            file: json_file_name,
+        },
+    );
+}
+
+pub(super) fn write_result_file(output: &JsonResult, path: &Path) {
+    write(path, serde_json::to_string(output).unwrap()).unwrap()
+}
+
+// The name of the current test binary, without the hash that cargo adds to it
+pub(super) fn binary_name() -> Option<String> {
+    let executable = current_exe().ok()?;
+    let name = executable.file_stem()?.to_str()?;
+    Some(without_hash(name).to_string())
+}
+
+fn without_hash(name: &str) -> &str {
+    match name.rsplit_once('-') {
+        Some((name, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            name
+        }
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+impl JsonResult {
+    // A run without tests, for the tests of the code that stores and combines runs
+    pub(super) fn example(timestamp: u64, total_uj: u128) -> JsonResult {
+        JsonResult {
+            schema_version: SCHEMA_VERSION,
+            run_id: format!("run-{timestamp}"),
+            invocation_id: "invocation".to_string(),
+            merged_runs: Vec::new(),
+            execution_timestamp: timestamp,
+            environment: Environment::default(),
+            git: None,
+            total_us: 10,
+            total_uj,
+            overhead_us: 1,
+            overhead_uj: 1,
+            test_threads: 1,
+            outlier_detection: OutlierConfig::default(),
+            tests: Vec::new(),
+            regressions: Vec::new(),
+            baseline_deviations: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_name_without_hash() {
+        assert_eq!(without_hash("integration-0123456789abcdef"), "integration");
+        assert_eq!(without_hash("my-tests-0123456789abcdef"), "my-tests");
+        assert_eq!(without_hash("my-tests"), "my-tests");
+    }
 
-    create_dir_all("target/coppers_results").unwrap();
-    let json_file_name =
-        format!("target/coppers_results/coppers_results-{execution_timestamp}.json");
-    let mut file = File::create(json_file_name).unwrap();
-    file.write_all(output_json.as_bytes()).unwrap()
+    #[test]
+    fn merge_results() {
+        assert!(JsonResult::merge(Vec::new()).is_none());
+        let merged = JsonResult::merge(vec![
+            JsonResult::example(20, 100),
+            JsonResult::example(10, 50),
+        ])
+        .unwrap();
+        assert_eq!(merged.execution_timestamp, 20);
+        assert_eq!(merged.total_uj, 150);
+        assert_eq!(merged.total_us, 20);
+        assert_eq!(merged.overhead_uj, 2);
+        assert_eq!(merged.merged_runs, ["run-20", "run-10"]);
+        assert_eq!(merged.invocation_id, "invocation");
+    }
 }
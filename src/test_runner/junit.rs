@@ -0,0 +1,365 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The results of a run in the JUnit XML format, which most CI systems can show. Every test binary
+// is a test suite, and the energy of every test is added as properties of its test case, so it
+// shows up next to whether the test passed. Tests that regressed or deviate from the baseline
+// make the run fail, so they are failures as well.
+
+use super::json::JsonResult;
+use super::regression::Regression;
+use super::{CompletedTest, TestResult};
+use crate::baseline::Pending;
+use std::fmt::Write;
+use std::fs::{create_dir_all, write};
+use std::path::{Path, PathBuf};
+
+// Test suite of the tests of which the test binary is unknown
+const DEFAULT_SUITE: &str = "coppers";
+
+pub(crate) fn write_junit(result: &JsonResult, path: &Path) -> Result<(), String> {
+    if let Some(directory) = path.parent() {
+        create_dir_all(directory)
+            .map_err(|e| format!("Could not create {}: {e}", directory.display()))?;
+    }
+    write(path, to_junit(result)).map_err(|e| format!("Could not write {}: {e}", path.display()))
+}
+
+// Under `cargo test`, every test binary writes its own results, so the name of the binary is
+// added to the file name. `cargo coppers test` writes the merged results to the file itself.
+pub(super) fn junit_path_of(path: &Path, binary: Option<&str>) -> PathBuf {
+    let Some(binary) = binary else {
+        return path.to_path_buf();
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}-{binary}.{}", extension.to_string_lossy()),
+        None => format!("{stem}-{binary}"),
+    };
+    path.with_file_name(name)
+}
+
+fn to_junit(result: &JsonResult) -> String {
+    // The test suites are in the order in which their first test ran
+    let mut suites: Vec<(&str, Vec<&CompletedTest>)> = Vec::new();
+    for test in &result.tests {
+        let name = test.binary.as_deref().unwrap_or(DEFAULT_SUITE);
+        match suites.iter_mut().find(|(suite, _)| *suite == name) {
+            Some((_, tests)) => tests.push(test),
+            None => suites.push((name, vec![test])),
+        }
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let all: Vec<&CompletedTest> = result.tests.iter().collect();
+    writeln!(
+        xml,
+        "<testsuites name=\"{DEFAULT_SUITE}\" {}>",
+        counts(result, &all)
+    )
+    .unwrap();
+    for (name, tests) in &suites {
+        writeln!(
+            xml,
+            "  <testsuite name=\"{}\" hostname=\"{}\" {}>",
+            escape(name),
+            escape(&result.environment.hostname),
+            counts(result, tests)
+        )
+        .unwrap();
+        xml.push_str("    <properties>\n");
+        property(&mut xml, 6, "run_id", &result.run_id);
+        property(&mut xml, 6, "invocation_id", &result.invocation_id);
+        property(
+            &mut xml,
+            6,
+            "execution_timestamp",
+            result.execution_timestamp,
+        );
+        if let Some(head) = result.git.as_ref().and_then(|git| git.head.as_ref()) {
+            property(&mut xml, 6, "git_head", head);
+        }
+        xml.push_str("    </properties>\n");
+        for test in tests {
+            test_case(&mut xml, result, name, test);
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn test_case(xml: &mut String, result: &JsonResult, suite: &str, test: &CompletedTest) {
+    writeln!(
+        xml,
+        "    <testcase name=\"{}\" classname=\"{}\" time=\"{}\">",
+        escape(&test.name),
+        escape(suite),
+        seconds(test.us.unwrap_or_default())
+    )
+    .unwrap();
+
+    xml.push_str("      <properties>\n");
+    if let Some(uj) = test.uj {
+        property(xml, 8, "energy_uj", uj);
+    }
+    if let Some(us) = test.us {
+        property(xml, 8, "time_us", us);
+    }
+    property(xml, 8, "iterations", test.counted_repeats);
+    if let Some(uj) = test.average_uj() {
+        property(xml, 8, "energy_uj_per_iteration", uj);
+    }
+    if let Some(budget) = test.options.budget_uj {
+        property(xml, 8, "budget_uj", budget);
+        let status = match test.state {
+            TestResult::OverBudget => "over",
+            TestResult::Passed => "within",
+            _ => "unknown",
+        };
+        property(xml, 8, "budget_status", status);
+    }
+    let regression = regression_of(result, test);
+    if let Some(regression) = regression {
+        property(xml, 8, "regression_increase", regression.increase);
+    }
+    let deviation = deviation_of(result, test);
+    if let Some(accepted_uj) = deviation.and_then(|deviation| deviation.accepted_uj) {
+        property(xml, 8, "baseline_uj", accepted_uj);
+    }
+    xml.push_str("      </properties>\n");
+
+    let failure = match &test.state {
+        TestResult::Failed(message) => Some((
+            "Failed",
+            message.clone().unwrap_or_else(|| "test failed".to_string()),
+        )),
+        TestResult::TimedOut => Some(("TimedOut", "test exceeded its timeout".to_string())),
+        TestResult::OverBudget => Some((
+            "OverBudget",
+            format!(
+                "test consumed {} μJ per iteration, which exceeds its budget of {} μJ",
+                test.uj_per_iteration().unwrap_or_default(),
+                test.options.budget_uj.unwrap_or_default()
+            ),
+        )),
+        TestResult::Passed | TestResult::Ignored => None,
+    };
+    // A test only has a single failure, of which the state of the test is the most important
+    let failure = failure.or_else(|| {
+        let regression = regression.map(|regression| {
+            (
+                "Regression",
+                format!(
+                    "test consumed {:.0} μJ per iteration, which is {:.1}% more than the {:.0} μJ of the previous runs",
+                    regression.current_uj, regression.increase, regression.baseline_uj
+                ),
+            )
+        });
+        let deviation = deviation.map(|deviation| {
+            let message = match deviation.accepted_uj {
+                Some(accepted_uj) => format!(
+                    "test consumed {} μJ per iteration, which deviates more than {}% from the {accepted_uj} μJ of the baseline",
+                    deviation.uj, deviation.tolerance
+                ),
+                None => format!(
+                    "test consumed {} μJ per iteration, and is not part of the baseline",
+                    deviation.uj
+                ),
+            };
+            ("BaselineDeviation", message)
+        });
+        regression.or(deviation)
+    });
+    if let Some((kind, message)) = failure {
+        writeln!(
+            xml,
+            "      <failure type=\"{kind}\" message=\"{}\">{}</failure>",
+            escape(&message),
+            escape(&message)
+        )
+        .unwrap();
+    }
+    if test.state == TestResult::Ignored {
+        xml.push_str("      <skipped/>\n");
+    }
+    if let Some(stdout) = &test.stdout {
+        writeln!(
+            xml,
+            "      <system-out>{}</system-out>",
+            escape(&String::from_utf8_lossy(stdout))
+        )
+        .unwrap();
+    }
+    xml.push_str("    </testcase>\n");
+}
+
+// The attributes with the amount of tests, failures and skipped tests, and the total time
+fn counts(result: &JsonResult, tests: &[&CompletedTest]) -> String {
+    let failures = tests
+        .iter()
+        .filter(|test| {
+            matches!(
+                test.state,
+                TestResult::Failed(_) | TestResult::TimedOut | TestResult::OverBudget
+            ) || regression_of(result, test).is_some()
+                || deviation_of(result, test).is_some()
+        })
+        .count();
+    let skipped = tests
+        .iter()
+        .filter(|test| test.state == TestResult::Ignored)
+        .count();
+    let us: u128 = tests.iter().filter_map(|test| test.us).sum();
+    format!(
+        "tests=\"{}\" failures=\"{failures}\" errors=\"0\" skipped=\"{skipped}\" time=\"{}\"",
+        tests.len(),
+        seconds(us)
+    )
+}
+
+fn regression_of<'a>(result: &'a JsonResult, test: &CompletedTest) -> Option<&'a Regression> {
+    result
+        .regressions
+        .iter()
+        .find(|regression| regression.name == test.name && regression.binary == test.binary)
+}
+
+fn deviation_of<'a>(result: &'a JsonResult, test: &CompletedTest) -> Option<&'a Pending> {
+    result
+        .baseline_deviations
+        .iter()
+        .find(|deviation| deviation.name == test.name && deviation.binary == test.binary)
+}
+
+fn property(xml: &mut String, indent: usize, name: &str, value: impl ToString) {
+    writeln!(
+        xml,
+        "{:indent$}<property name=\"{name}\" value=\"{}\"/>",
+        "",
+        escape(&value.to_string())
+    )
+    .unwrap();
+}
+
+fn seconds(us: u128) -> String {
+    format!("{:.6}", us as f64 / 1_000_000.0)
+}
+
+// Control characters other than whitespace are not allowed in XML at all, so they are left out
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn junit_of_run() {
+        let mut result = JsonResult::example(10, 100);
+        result.tests = vec![
+            CompletedTest {
+                binary: Some("integration".to_string()),
+                state: TestResult::Passed,
+                uj: Some(30),
+                us: Some(1500),
+                counted_repeats: 2,
+                ..CompletedTest::empty("tests::a".to_string())
+            },
+            CompletedTest {
+                binary: Some("integration".to_string()),
+                state: TestResult::Failed(Some("expected <1> & got 2".to_string())),
+                stdout: Some(b"some output\x1b[0m".to_vec()),
+                ..CompletedTest::empty("tests::b".to_string())
+            },
+            CompletedTest::empty("tests::c".to_string()),
+        ];
+        result.tests[0].options.budget_uj = Some(20);
+
+        let xml = to_junit(&result);
+        assert!(xml.contains(
+            "<testsuites name=\"coppers\" tests=\"3\" failures=\"1\" errors=\"0\" skipped=\"1\" time=\"0.001500\">"
+        ));
+        assert!(xml.contains("<testsuite name=\"integration\" hostname=\"\" tests=\"2\""));
+        assert!(xml.contains("<testsuite name=\"coppers\" hostname=\"\" tests=\"1\""));
+        assert!(xml.contains("<property name=\"run_id\" value=\"run-10\"/>"));
+        assert!(xml
+            .contains("<testcase name=\"tests::a\" classname=\"integration\" time=\"0.001500\">"));
+        assert!(xml.contains("<property name=\"energy_uj_per_iteration\" value=\"15\"/>"));
+        assert!(xml.contains("<property name=\"budget_status\" value=\"within\"/>"));
+        assert!(xml.contains("message=\"expected &lt;1&gt; &amp; got 2\""));
+        assert!(xml.contains("<system-out>some output[0m</system-out>"));
+        assert!(xml.contains("<skipped/>"));
+    }
+
+    #[test]
+    fn junit_of_regression_and_deviation() {
+        let mut result = JsonResult::example(10, 100);
+        let test = |name: &str| CompletedTest {
+            binary: Some("integration".to_string()),
+            state: TestResult::Passed,
+            ..CompletedTest::empty(name.to_string())
+        };
+        result.tests = vec![test("tests::a"), test("tests::b"), test("tests::c")];
+        result.regressions = vec![Regression {
+            name: "tests::a".to_string(),
+            binary: Some("integration".to_string()),
+            baseline_uj: 100.0,
+            current_uj: 150.0,
+            increase: 50.0,
+            p_value: None,
+        }];
+        result.baseline_deviations = vec![Pending {
+            name: "tests::b".to_string(),
+            binary: Some("integration".to_string()),
+            uj: 200,
+            accepted_uj: Some(100),
+            tolerance: 10.0,
+            environment: None,
+        }];
+
+        let xml = to_junit(&result);
+        assert!(xml.contains("<testsuites name=\"coppers\" tests=\"3\" failures=\"2\""));
+        assert!(xml.contains("<property name=\"regression_increase\" value=\"50\"/>"));
+        assert!(xml.contains("<failure type=\"Regression\""));
+        assert!(xml.contains("<property name=\"baseline_uj\" value=\"100\"/>"));
+        assert!(xml.contains("<failure type=\"BaselineDeviation\""));
+    }
+
+    #[test]
+    fn junit_path_per_binary() {
+        assert_eq!(
+            junit_path_of(Path::new("/crate/target/junit.xml"), Some("integration")),
+            Path::new("/crate/target/junit-integration.xml")
+        );
+        assert_eq!(
+            junit_path_of(Path::new("/crate/target/junit.xml"), None),
+            Path::new("/crate/target/junit.xml")
+        );
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Runs the tests that are collected by libtest, which is only possible with a nightly compiler
+
+use super::{run_tests, TestDefinition, TestFunction, TestKind};
+use crate::registry::ShouldPanic;
+use test::{StaticBenchFn, StaticTestFn, TestDescAndFn, TestFn};
+
+pub fn runner(tests: &[&test::TestDescAndFn]) {
+    test_main(tests.iter().map(make_owned_test).collect())
+}
+
+// Run tests that are not known at compile time, like tests that are generated by a harness.
+// This is the equivalent of `test::test_main` of libtest.
+pub fn test_main(tests: Vec<TestDescAndFn>) {
+    run_tests(tests.into_iter().map(TestDefinition::from).collect())
+}
+
+fn make_owned_test(test: &&TestDescAndFn) -> TestDescAndFn {
+    let testfn = match test.testfn {
+        StaticTestFn(f) => StaticTestFn(f),
+        StaticBenchFn(f) => StaticBenchFn(f),
+        TestFn::StaticBenchAsTestFn(f) => TestFn::StaticBenchAsTestFn(f),
+        _ => panic!("non-static tests passed to coppers::runner, use coppers::test_main instead"),
+    };
+    TestDescAndFn {
+        testfn,
+        desc: test.desc.clone(),
+    }
+}
+
+impl From<TestDescAndFn> for TestDefinition {
+    fn from(test: TestDescAndFn) -> Self {
+        let kind = match test.testfn {
+            TestFn::StaticBenchFn(_)
+            | TestFn::StaticBenchAsTestFn(_)
+            | TestFn::DynBenchFn(_)
+            | TestFn::DynBenchAsTestFn(_) => TestKind::Bench,
+            TestFn::StaticTestFn(_) | TestFn::DynTestFn(_) => TestKind::Test,
+        };
+        let function = match test.testfn {
+            TestFn::StaticTestFn(f) => TestFunction::Repeatable(Box::new(f)),
+            // Benchmarks run a single iteration with `run_once`, just like libtest does when it
+            // runs benchmarks as tests
+            TestFn::StaticBenchFn(f) | TestFn::StaticBenchAsTestFn(f) => {
+                TestFunction::Repeatable(Box::new(move || test::bench::run_once(f)))
+            }
+            TestFn::DynBenchFn(f) | TestFn::DynBenchAsTestFn(f) => {
+                TestFunction::Repeatable(Box::new(move || test::bench::run_once(|b| f(b))))
+            }
+            TestFn::DynTestFn(f) => TestFunction::Once(f),
+        };
+        let should_panic = match test.desc.should_panic {
+            test::ShouldPanic::No => ShouldPanic::No,
+            test::ShouldPanic::Yes => ShouldPanic::Yes,
+            test::ShouldPanic::YesWithMessage(msg) => ShouldPanic::YesWithMessage(msg),
+        };
+        TestDefinition {
+            name: test.desc.name.to_string(),
+            ignore: test.desc.ignore,
+            should_panic,
+            kind,
+            function,
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Where the results, the report and the pending baseline files are written to. By default, all
+// of them are in the target directory of cargo, which is shared by all members of a workspace and
+// can be moved elsewhere with `CARGO_TARGET_DIR`.
+
+use crate::config::Config;
+use std::env;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+// Overrides the results directory, unless it is set in the configuration
+const RESULTS_DIRECTORY_VARIABLE: &str = "COPPERS_RESULTS_DIR";
+
+const RESULTS_DIRECTORY_NAME: &str = "coppers_results";
+#[cfg_attr(not(feature = "visualization"), allow(dead_code))]
+const REPORT_DIRECTORY_NAME: &str = "coppers_report";
+const EXPORT_DIRECTORY_NAME: &str = "coppers_export";
+
+// Cargo marks the root of its target directory with this file
+const TARGET_DIRECTORY_TAG: &str = "CACHEDIR.TAG";
+
+// The directory of the results of the crate in `root`, of which `executable` is a test binary.
+// The configuration takes precedence over `COPPERS_RESULTS_DIR`, which takes precedence over the
+// target directory.
+pub(crate) fn results_directory(
+    config: &Config,
+    root: &Path,
+    executable: Option<&Path>,
+) -> PathBuf {
+    if let Some(directory) = &config.results_dir {
+        return root.join(directory);
+    }
+    if let Some(directory) = env::var_os(RESULTS_DIRECTORY_VARIABLE) {
+        return PathBuf::from(directory);
+    }
+    target_directory(root, executable).join(RESULTS_DIRECTORY_NAME)
+}
+
+// The report is written next to the results
+#[cfg_attr(not(feature = "visualization"), allow(dead_code))]
+pub(crate) fn report_directory(results_directory: &Path) -> PathBuf {
+    results_directory
+        .parent()
+        .unwrap_or(results_directory)
+        .join(REPORT_DIRECTORY_NAME)
+}
+
+// Just like the report, exports are written next to the results
+pub(crate) fn export_directory(results_directory: &Path) -> PathBuf {
+    results_directory
+        .parent()
+        .unwrap_or(results_directory)
+        .join(EXPORT_DIRECTORY_NAME)
+}
+
+// Test binaries are built somewhere in the target directory, which is the most reliable way to
+// find it. Otherwise, `CARGO_TARGET_DIR` or the `target` directory in the root of the workspace
+// is used, just like cargo does.
+pub(crate) fn target_directory(root: &Path, executable: Option<&Path>) -> PathBuf {
+    let built_in = executable.and_then(|executable| {
+        executable
+            .ancestors()
+            .find(|directory| directory.join(TARGET_DIRECTORY_TAG).is_file())
+    });
+    if let Some(directory) = built_in {
+        return directory.to_path_buf();
+    }
+    let workspace = workspace_root(root);
+    match env::var_os("CARGO_TARGET_DIR") {
+        Some(directory) => workspace.join(directory),
+        None => workspace.join("target"),
+    }
+}
+
+// The closest directory with a `Cargo.toml` that defines a workspace, or the crate itself if it
+// is not part of a workspace
+fn workspace_root(root: &Path) -> PathBuf {
+    root.ancestors()
+        .find(|directory| defines_workspace(&directory.join("Cargo.toml")))
+        .unwrap_or(root)
+        .to_path_buf()
+}
+
+fn defines_workspace(manifest: &Path) -> bool {
+    read_to_string(manifest)
+        .ok()
+        .and_then(|contents| contents.parse::<toml::Value>().ok())
+        .is_some_and(|manifest| manifest.get("workspace").is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::baseline::pending_directory;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn results_directory_in_workspace() {
+        let workspace = env::temp_dir().join(format!("coppers-location-{}", std::process::id()));
+        let member = workspace.join("member");
+        create_dir_all(&member).unwrap();
+        write(
+            workspace.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"member\"]\n",
+        )
+        .unwrap();
+        write(member.join("Cargo.toml"), "[package]\nname = \"member\"\n").unwrap();
+        assert_eq!(workspace_root(&member), workspace);
+        assert_eq!(workspace_root(&workspace), workspace);
+
+        // The target directory of a test binary is found from the binary itself
+        let deps = workspace.join("elsewhere/debug/deps");
+        create_dir_all(&deps).unwrap();
+        write(workspace.join("elsewhere/CACHEDIR.TAG"), "").unwrap();
+        assert_eq!(
+            target_directory(&member, Some(&deps.join("tests-0123456789abcdef"))),
+            workspace.join("elsewhere")
+        );
+        // The pending baseline files of a member end up in the shared target directory as well
+        assert_eq!(
+            pending_directory(&target_directory(
+                &member,
+                Some(&deps.join("tests-0123456789abcdef"))
+            )),
+            workspace.join("elsewhere/coppers_baseline")
+        );
+
+        let config = Config {
+            results_dir: Some(PathBuf::from("energy")),
+            ..Config::default()
+        };
+        assert_eq!(
+            results_directory(&config, &member, None),
+            member.join("energy")
+        );
+        remove_dir_all(workspace).unwrap();
+    }
+
+    #[test]
+    fn report_next_to_results() {
+        assert_eq!(
+            report_directory(Path::new("/crate/target/coppers_results")),
+            Path::new("/crate/target/coppers_report")
+        );
+    }
+}
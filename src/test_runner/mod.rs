@@ -14,221 +14,885 @@
 
 // Note that this is heavily inspired by libtest that is part of the Rust language.
 
-use self::json::write_to_json;
-use super::sensors::{RAPLSensor, Sensor};
+use self::attribution::attribute;
+use self::capture::{Capture, ThreadCapture};
+use self::formatters::{formatter, Summary};
+use self::json::{binary_name, write_result_file};
+use self::junit::junit_path_of;
+use self::outliers::detect_outliers;
+use self::regression::find_regressions;
+use crate::baseline::{pending_directory, Baseline, Pending};
+use crate::config::{
+    crate_root, BaselineConfig, Config, IsolationMode, OutlierConfig, OutputFormat,
+};
+use crate::region::{self, add_region, RegionMeasurement};
+use crate::registry::{ShouldPanic, TestSettings};
+use crate::sensors::{default_sensor, energy_unavailable_reason, thread_cpu_time};
 use std::any::Any;
-use std::io::{self, Write};
+use std::collections::VecDeque;
 use std::panic::{catch_unwind, AssertUnwindSafe};
-use std::sync::{Arc, Mutex};
-use test::{StaticTestFn, TestDescAndFn};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
+mod attribution;
+mod budget;
+mod capture;
+mod environment;
+mod export;
+mod formatters;
+mod git;
+mod isolation;
 mod json;
+mod junit;
+#[cfg(coppers_nightly)]
+mod libtest;
+mod location;
+mod outliers;
+mod regression;
+mod run_id;
+mod schema;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
 
 #[cfg(feature = "visualization")]
 mod visualization;
+#[cfg(feature = "visualization")]
+pub(crate) use self::visualization::visualize;
 
-pub(crate) const REPEAT_TESTS_AMOUNT_OF_TIMES: usize = 15;
+pub use self::budget::set_budget;
+pub use self::environment::Environment;
+pub(crate) use self::export::export_csv;
+pub use self::git::GitInfo;
+pub use self::json::JsonResult;
+pub(crate) use self::json::RESULT_FILE_VARIABLE;
+pub(crate) use self::junit::write_junit;
+#[cfg(coppers_nightly)]
+pub use self::libtest::{runner, test_main};
+pub(crate) use self::location::{results_directory, target_directory};
+pub(crate) use self::run_id::{own_invocation_id, INVOCATION_VARIABLE};
+#[cfg(feature = "sqlite")]
+pub(crate) use self::sqlite::{SqliteStore, DATABASE_FILE_NAME};
+pub(crate) use self::store::{import, open_store, JsonStore, Query, ResultStore};
 
-pub fn runner(tests: &[&test::TestDescAndFn]) {
-    let tests: Vec<_> = tests.iter().map(make_owned_test).collect();
+pub(crate) const REPEAT_TESTS_AMOUNT_OF_TIMES: usize = 15;
 
-    println!("Running {} tests", tests.len());
+// Just like libtest, warn about tests that take longer than a minute
+const TEST_WARN_TIMEOUT: Duration = Duration::from_secs(60);
 
-    let mut ignored = 0;
-    //let mut filtered = 0;
+// Run all tests that are marked with `#[coppers::test]`. This is the entry point of a test
+// target with `harness = false`, and does not rely on libtest, so it works on stable as well.
+pub fn main() {
+    let tests = TestSettings::all()
+        .into_iter()
+        .map(|settings| TestDefinition {
+            name: settings.test_name(),
+            ignore: settings.ignore,
+            should_panic: settings.should_panic,
+            kind: TestKind::Test,
+            function: TestFunction::Repeatable(Box::new(settings.function)),
+        })
+        .collect();
+    run_tests(tests)
+}
 
-    let mut passed_tests = Vec::new();
-    let mut failed_tests = Vec::new();
+fn run_tests(tests: Vec<TestDefinition>) {
+    let config = Config::load().unwrap_or_else(|e| panic!("{e}"));
 
-    let mut test_uj = 0;
-    let mut test_us = 0;
+    // This process is started by another runner to run only a single test
+    if let Some(name) = &config.child_test {
+        isolation::run_child(tests, name, &config);
+        return;
+    }
 
-    let mut sensor =
-        RAPLSensor::new("/sys/devices/virtual/powercap/intel-rapl/intel-rapl:0".to_string())
+    let mut formatter = formatter(config.format);
+    let names: Vec<&str> = tests.iter().map(|test| test.name.as_str()).collect();
+    formatter.write_run_start(&names).unwrap();
+    if let Some(reason) = energy_unavailable_reason() {
+        formatter
+            .write_message(&format!(
+                "warning: only time is measured, because energy cannot be measured: {reason}"
+            ))
             .unwrap();
+    }
+
+    let mut sensor = default_sensor();
     sensor.start_measuring();
 
-    for test in tests {
-        let result = run_test(test);
-        print_test_result(&result);
-        match result.state {
-            TestResult::Passed => {
-                test_uj += result.uj.unwrap();
-                test_us += result.us.unwrap();
-                passed_tests.push(result);
-            }
-            TestResult::Failed(_) => failed_tests.push(result),
-            TestResult::Ignored => ignored += 1,
-            //TestResult::Filtered => filtered += 1,
+    let test_threads = config.test_threads();
+    let results = if test_threads > 1 {
+        let mut results = run_parallel(tests, test_threads, &config);
+        // The measured energy of the tests overlaps, so it is attributed to the tests once all
+        // of them are done
+        attribute(&mut results);
+        results.iter_mut().for_each(CompletedTest::check_budget);
+        // The results are only known at the end, so every test starts right before its result
+        for test in &results {
+            formatter.write_test_start(&test.name).unwrap();
+            formatter.write_result(test).unwrap();
         }
-    }
+        results
+    } else {
+        tests
+            .into_iter()
+            .map(|test| {
+                formatter.write_test_start(&test.name).unwrap();
+                let result = run_test(test, &config);
+                formatter.write_result(&result).unwrap();
+                result
+            })
+            .collect()
+    };
+
+    // The binary is known before the results are compared with the previous runs, which only
+    // contain the same test if it is part of the same binary
+    let binary = binary_name();
+    let results: Vec<CompletedTest> = results
+        .into_iter()
+        .map(|test| CompletedTest {
+            binary: binary.clone(),
+            ..test
+        })
+        .collect();
+
+    // Failed tests and outliers also consumed energy, so they count towards the tests and not
+    // towards the overhead, even if they are excluded from the test itself
+    let (test_uj, test_us) = results
+        .iter()
+        .map(CompletedTest::consumed)
+        .fold((0, 0), |(uj, us), (test_uj, test_us)| {
+            (uj + test_uj, us + test_us)
+        });
+
+    let failed = results.iter().any(|t| {
+        matches!(
+            t.state,
+            TestResult::Failed(_) | TestResult::TimedOut | TestResult::OverBudget
+        )
+    });
 
     sensor.stop_measuring();
     let total_us = sensor.get_elapsed_time_us();
     let total_uj = sensor.get_measured_uj();
 
-    let overhead_us = total_us - test_us;
-    let overhead_uj = total_uj - test_uj;
-
-    print_failures(&failed_tests).unwrap();
+    // Tests that ran in parallel can take more time together than the run as a whole
+    let overhead_us = total_us.saturating_sub(test_us);
+    let overhead_uj = total_uj.saturating_sub(test_uj);
 
-    println!("test result: {}.\n\t{} passed;\n\t{} failed;\n\t{ignored} ignored;\n\tfinished in {total_us} μs consuming {total_uj} μJ\n\tspend {test_us} μs and {test_uj} μJ on tests\n\tspend {overhead_us} μs and {overhead_uj} μJ on overhead", passed(failed_tests.is_empty()), passed_tests.len(), failed_tests.len());
+    let results_directory = results_directory(
+        &config,
+        &crate_root(),
+        std::env::current_exe().ok().as_deref(),
+    );
+    let mut store = open_store(config.store, &results_directory).unwrap_or_else(|e| panic!("{e}"));
+    // The results of this run are not stored yet, so they are not part of the history
+    let (regressions, history) = if config.regression.enabled {
+        let history = store
+            .runs(&Query {
+                environment: Some(&Environment::current()),
+                limit: Some(config.regression.history),
+            })
+            .unwrap_or_else(|e| panic!("{e}"));
+        let regressions = find_regressions(&results, &history, &config.regression);
+        (regressions, Some(history.len()))
+    } else {
+        (Vec::new(), None)
+    };
+    let deviations = if config.baseline.enabled {
+        let (deviations, elsewhere) = check_baseline(&results, binary.as_deref(), &config.baseline);
+        if elsewhere > 0 {
+            formatter
+                .write_message(&format!(
+                    "warning: {elsewhere} tests are not compared with the baseline, because their energy is accepted on other hardware or with another sensor"
+                ))
+                .unwrap();
+        }
+        deviations
+    } else {
+        Vec::new()
+    };
+    let success = !failed && regressions.is_empty() && deviations.is_empty();
 
-    // Write test results to JSON file
-    write_to_json(passed_tests, total_us, total_uj, overhead_us, overhead_uj);
+    formatter
+        .write_run_finish(&Summary {
+            tests: &results,
+            regressions: &regressions,
+            history,
+            deviations: &deviations,
+            success,
+            total_uj,
+            total_us,
+            test_uj,
+            test_us,
+            overhead_uj,
+            overhead_us,
+        })
+        .unwrap();
 
-    #[cfg(feature = "visualization")]
-    self::visualization::visualize();
-}
+    let result = JsonResult {
+        total_us,
+        total_uj,
+        overhead_us,
+        overhead_uj,
+        regressions,
+        baseline_deviations: deviations,
+        ..JsonResult::new(results, &config)
+    };
 
-fn print_failures(tests: &Vec<CompletedTest>) -> std::io::Result<()> {
-    if !tests.is_empty() {
-        let stdout = io::stdout();
-        let mut handle = stdout.lock();
-        for test in tests {
-            if let Some(captured) = &test.stdout {
-                handle.write_fmt(format_args!("\n---- {} stdout ----\n", test.name))?;
-                handle.write_all(captured)?;
-                handle.write_all(b"\n")?;
+    // `cargo coppers test` merges the results of all test binaries into a single run, and
+    // writes that instead
+    match std::env::var_os(RESULT_FILE_VARIABLE) {
+        Some(path) => write_result_file(&result, path.as_ref()),
+        None => {
+            store.save(&result).unwrap_or_else(|e| panic!("{e}"));
+            if let Some(rows) = config.csv {
+                let path = export_csv(store.as_ref(), rows, &results_directory)
+                    .unwrap_or_else(|e| panic!("{e}"));
+                formatter
+                    .write_message(&format!("exported the results to {}", path.display()))
+                    .unwrap();
             }
-        }
-        handle.write_all(b"\nfailures:\n")?;
-        for test in tests {
-            handle.write_fmt(format_args!("\t{}", test.name))?;
-            if let TestResult::Failed(Some(msg)) = &test.state {
-                handle.write_fmt(format_args!(": {}\n", msg))?;
+            // Every test binary writes its own file, so they do not overwrite each other
+            if let Some(path) = &config.junit {
+                let path = junit_path_of(&crate_root().join(path), binary.as_deref());
+                write_junit(&result, &path).unwrap_or_else(|e| panic!("{e}"));
+            }
+
+            // The tests ran already, so a report that cannot be generated does not fail the run
+            #[cfg(feature = "visualization")]
+            if let Err(e) =
+                self::visualization::visualize(store.as_ref(), &result, &results_directory)
+            {
+                formatter.write_message(&format!("warning: {e}")).unwrap();
             }
         }
-        handle.write_all(b"\n")?;
     }
-    Ok(())
+
+    // Just like libtest, exit with 101 when a test did not pass, so `cargo test` fails as well
+    if !success {
+        std::process::exit(101);
+    }
 }
 
-fn print_test_result(test: &CompletedTest) {
-    match test.state {
-        TestResult::Passed => {
-            let uj = test.uj.unwrap();
-            let us = test.us.unwrap();
-            println!(
-                "test {} ... {} - [{uj} μJ in {us} μs]",
-                test.name,
-                passed(true)
-            )
-        }
-        TestResult::Failed(_) => {
-            println!("test {} ... {}", test.name, passed(false))
+// Run the tests on a pool of threads, which each pick the next test from a shared queue
+fn run_parallel(
+    tests: Vec<TestDefinition>,
+    test_threads: usize,
+    config: &Config,
+) -> Vec<CompletedTest> {
+    let queue = Mutex::new(VecDeque::from(tests));
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..test_threads {
+            scope.spawn(|| loop {
+                let test = queue.lock().unwrap().pop_front();
+                match test {
+                    Some(test) => {
+                        let result = run_test(test, config);
+                        results.lock().unwrap().push(result);
+                    }
+                    None => break,
+                }
+            });
         }
-        _ => {}
-    }
+    });
+    results.into_inner().unwrap()
 }
 
-fn passed(condition: bool) -> &'static str {
-    if condition {
-        "ok"
-    } else {
-        "FAILED"
-    }
+// Compare the tests that ran completely with the baseline, and write a pending file for every
+// test that deviates from it. Tests of which the energy is accepted in another environment are
+// not compared. Returns the deviations and the amount of tests that are not compared.
+fn check_baseline(
+    results: &[CompletedTest],
+    binary: Option<&str>,
+    config: &BaselineConfig,
+) -> (Vec<Pending>, usize) {
+    let root = crate_root();
+    let baseline = Baseline::load(&root).unwrap_or_else(|e| panic!("{e}"));
+    let pending_directory = pending_directory(&target_directory(
+        &root,
+        std::env::current_exe().ok().as_deref(),
+    ));
+    let environment = Environment::current();
+    let (elsewhere, comparable): (Vec<_>, Vec<_>) = results
+        .iter()
+        .filter(|test| matches!(test.state, TestResult::Passed | TestResult::OverBudget))
+        .partition(|test| baseline.recorded_elsewhere(binary, &test.name, &environment));
+    let deviations = comparable
+        .into_iter()
+        .filter_map(|test| {
+            let uj = test.uj_per_iteration()?;
+            match baseline.compare(binary, &test.name, uj, config.tolerance) {
+                Some(pending) => {
+                    Pending {
+                        environment: Some(environment.clone()),
+                        ..pending.clone()
+                    }
+                    .write(&pending_directory)
+                    .unwrap_or_else(|e| panic!("{e}"));
+                    Some(pending)
+                }
+                None => {
+                    Pending::remove(&pending_directory, binary, &test.name);
+                    None
+                }
+            }
+        })
+        .collect();
+    (deviations, elsewhere.len())
 }
 
-fn make_owned_test(test: &&TestDescAndFn) -> TestDescAndFn {
-    match test.testfn {
-        StaticTestFn(f) => TestDescAndFn {
-            testfn: StaticTestFn(f),
-            desc: test.desc.clone(),
-        },
-        _ => panic!("non-static tests passed to test::test_main_static"),
-    }
+// A test as the runner sees it, regardless of how it was collected
+struct TestDefinition {
+    name: String,
+    ignore: bool,
+    should_panic: ShouldPanic,
+    kind: TestKind,
+    function: TestFunction,
+}
+
+enum TestFunction {
+    Repeatable(Box<dyn FnMut() -> Result<(), String> + Send>),
+    // A test that can only be called once, like a dynamic test of libtest
+    #[cfg_attr(not(coppers_nightly), allow(dead_code))]
+    Once(Box<dyn FnOnce() -> Result<(), String> + Send>),
 }
 
-#[derive(serde::Serialize, Debug, PartialEq)]
-enum TestResult {
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub enum TestResult {
     Passed,
     Failed(Option<String>),
+    TimedOut,
+    // The test passed, but consumed more energy per iteration than its budget
+    OverBudget,
     Ignored,
     // TODO: add Filtered
 }
 
-#[derive(serde::Serialize, Debug, PartialEq)]
-pub(crate) struct CompletedTest {
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct CompletedTest {
     name: String,
+    // The test binary that the test is part of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    binary: Option<String>,
+    #[serde(default)]
+    kind: TestKind,
+    #[serde(default)]
+    options: TestOptions,
     state: TestResult,
+    // Aggregated energy and time of the iterations that are counted
     uj: Option<u128>,
     us: Option<u128>,
+    // The amount of iterations that are included in `uj` and `us`
+    counted_repeats: usize,
+    // The amount of iterations that are flagged as outlier
+    outliers: usize,
+    // Raw measurements of every iteration that has been executed, including outliers
+    iterations: Vec<Iteration>,
+    // Energy and time of the named regions, summed over the iterations that are counted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    regions: Vec<RegionMeasurement>,
+    // Energy and time of the whole process of a test that ran in isolation, including
+    // starting and stopping the process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    process_uj: Option<u128>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    process_us: Option<u128>,
+    // CPU time that is spent by the thread that ran the test
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cpu_us: Option<u128>,
+    #[serde(default)]
+    measurement: Measurement,
+    // Energy of the whole package while the test ran, before a share of it was attributed to
+    // the test
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    package_uj: Option<u128>,
+    // The moments that the test started and finished
     #[serde(skip)]
+    window: Option<(Instant, Instant)>,
+    // Captured output of the test, which is only kept if the test failed
+    #[serde(
+        default,
+        serialize_with = "serialize_stdout",
+        deserialize_with = "deserialize_stdout",
+        skip_serializing_if = "Option::is_none"
+    )]
     stdout: Option<Vec<u8>>,
 }
 
+fn serialize_stdout<S: serde::Serializer>(
+    stdout: &Option<Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match stdout {
+        Some(stdout) => serializer.serialize_some(&String::from_utf8_lossy(stdout)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_stdout<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error> {
+    let stdout: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(stdout.map(String::into_bytes))
+}
+
+// Settings of a single test, which can be set with `#[coppers::test(...)]`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+struct TestOptions {
+    // The amount of measured iterations
+    repeats: usize,
+    // The amount of iterations that run before the measured iterations, which are not measured
+    warmup: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budget_uj: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    // Whether only the regions of the test are measured, instead of the whole test
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    regions_only: bool,
+}
+
+impl Default for TestOptions {
+    fn default() -> Self {
+        TestOptions {
+            repeats: REPEAT_TESTS_AMOUNT_OF_TIMES,
+            warmup: 0,
+            budget_uj: None,
+            group: None,
+            regions_only: false,
+        }
+    }
+}
+
+impl TestOptions {
+    fn of(test_name: &str) -> TestOptions {
+        let defaults = TestOptions::default();
+        match TestSettings::find(test_name) {
+            Some(settings) => TestOptions {
+                repeats: settings.repeats.unwrap_or(defaults.repeats),
+                warmup: settings.warmup.unwrap_or(defaults.warmup),
+                budget_uj: settings.budget_uj,
+                group: settings.group.map(str::to_string),
+                ..defaults
+            },
+            None => defaults,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TestKind {
+    #[default]
+    Test,
+    // Every iteration of a benchmark is a single iteration of the closure that is passed to
+    // `Bencher::iter`, including the code around it
+    Bench,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Measurement {
+    // The test had the package to itself, so all measured energy belongs to the test
+    #[default]
+    Exclusive,
+    // Other tests ran at the same time, so the test only got a share of the measured energy
+    Attributed,
+}
+
+// The last iteration of a test that timed out is incomplete, and covers the time between the
+// start of that iteration and the moment that the test timed out.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, PartialEq)]
+struct Iteration {
+    uj: u128,
+    us: u128,
+    outlier: bool,
+    // Energy and time of the named regions in this iteration
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    regions: Vec<RegionMeasurement>,
+    // Energy and time that are excluded from this iteration
+    #[serde(default, skip_serializing_if = "is_zero")]
+    excluded_uj: u128,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    excluded_us: u128,
+}
+
+fn is_zero(value: &u128) -> bool {
+    *value == 0
+}
+
+// Progress that the thread that runs a test reports back to the runner
+enum Progress {
+    Iteration(Iteration),
+    Finished {
+        state: TestResult,
+        cpu_time: Option<Duration>,
+        // The budget that the test set with `coppers::budget!`
+        budget_uj: Option<u128>,
+    },
+}
+
+// Read access to the results of a test, see `coppers::history`
+impl CompletedTest {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The test binary that the test is part of, if it is known
+    pub fn binary(&self) -> Option<&str> {
+        self.binary.as_deref()
+    }
+
+    pub fn state(&self) -> &TestResult {
+        &self.state
+    }
+
+    // Energy and time of the iterations that are counted
+    pub fn uj(&self) -> Option<u128> {
+        self.uj
+    }
+
+    pub fn us(&self) -> Option<u128> {
+        self.us
+    }
+
+    pub fn counted_repeats(&self) -> usize {
+        self.counted_repeats
+    }
+
+    // Energy per counted iteration, if the test ran completely
+    pub fn average_uj(&self) -> Option<f64> {
+        self.average(self.uj)
+    }
+
+    pub fn average_us(&self) -> Option<f64> {
+        self.average(self.us)
+    }
+
+    // Failed and ignored tests did not run completely, so their energy is not comparable with
+    // that of other runs. Tests that are over their budget did.
+    fn average(&self, total: Option<u128>) -> Option<f64> {
+        let completed = matches!(self.state, TestResult::Passed | TestResult::OverBudget);
+        match total {
+            Some(total) if completed && self.counted_repeats > 0 => {
+                Some(total as f64 / self.counted_repeats as f64)
+            }
+            _ => None,
+        }
+    }
+}
+
 impl CompletedTest {
     fn empty(name: String) -> Self {
         CompletedTest {
             name,
+            binary: None,
+            kind: TestKind::Test,
+            options: TestOptions::default(),
             state: TestResult::Ignored,
             uj: None,
             us: None,
+            counted_repeats: 0,
+            outliers: 0,
+            iterations: Vec::new(),
+            regions: Vec::new(),
+            process_uj: None,
+            process_us: None,
+            cpu_us: None,
+            measurement: Measurement::Exclusive,
+            package_uj: None,
+            window: None,
             stdout: None,
         }
     }
+
+    // All energy and time that is consumed by running this test
+    fn consumed(&self) -> (u128, u128) {
+        match (self.process_uj, self.process_us) {
+            (Some(uj), Some(us)) => (uj, us),
+            // The excluded parts of a test are still consumed by the test
+            _ => (
+                self.iterations.iter().map(|i| i.uj + i.excluded_uj).sum(),
+                self.iterations.iter().map(|i| i.us + i.excluded_us).sum(),
+            ),
+        }
+    }
 }
 
-fn run_test(test: test::TestDescAndFn) -> CompletedTest {
+fn run_test(test: TestDefinition, config: &Config) -> CompletedTest {
+    let kind = test.kind;
+    let mut options = TestOptions::of(&test.name);
+    // A test that can only be called once has no calls left for warmup iterations
+    if matches!(test.function, TestFunction::Once(_)) {
+        options.repeats = 1;
+        options.warmup = 0;
+    }
+    options.regions_only = config.regions_only_of(&test.name);
+
+    let name = test.name.clone();
     // If a test is marked with #[ignore], it should not be executed
-    if test.desc.ignore {
-        CompletedTest::empty(test.desc.name.to_string())
+    let mut result = if test.ignore {
+        CompletedTest {
+            options,
+            ..CompletedTest::empty(name.clone())
+        }
+    } else if config.isolation.mode == IsolationMode::Process && config.child_test.is_none() {
+        isolation::run_in_process(&test, options, config)
     } else {
-        let mut sensor =
-            RAPLSensor::new("/sys/devices/virtual/powercap/intel-rapl/intel-rapl:0".to_string())
-                .unwrap();
+        run_on_thread(test, options, config)
+    };
+    result.kind = kind;
+    if let Some(budget_uj) = config.budget_of(&name) {
+        result.options.budget_uj = Some(budget_uj);
+    }
+    result.check_budget();
+    result
+}
 
-        // Use internal compiler function `set_output_capture` to capture the output of the
-        // tests.
-        let data = Arc::new(Mutex::new(Vec::new()));
-        io::set_output_capture(Some(data.clone()));
-
-        let mut uj = 0;
-        let mut us = 0;
-
-        let state = match test.testfn {
-            test::TestFn::StaticTestFn(f) => {
-                let mut state = TestResult::Ignored;
-                // Run the test function 100 times in a row
-                for _ in 0..REPEAT_TESTS_AMOUNT_OF_TIMES {
-                    let result = catch_unwind(AssertUnwindSafe(|| {
-                        sensor.start_measuring();
-                        f();
-                    }));
-                    sensor.stop_measuring();
-                    uj += sensor.get_measured_uj();
-                    us += sensor.get_elapsed_time_us();
+fn run_on_thread(test: TestDefinition, mut options: TestOptions, config: &Config) -> CompletedTest {
+    let name = test.name;
+    let timeout = config.timeout_of(&name);
+    let function = test.function;
+    let should_panic = test.should_panic;
+    let thread_options = options.clone();
 
-                    state = test_state(&test.desc, result);
-                    if state != TestResult::Passed {
-                        break;
-                    }
+    // This sensor measures the test as a whole, so the energy that is consumed so far is
+    // known when the test times out.
+    let mut sensor = default_sensor();
+
+    // Run the test on its own thread, so the runner can move on when the test hangs. The
+    // thread is named after the test, just like libtest does.
+    // A test that runs in its own process is the only test in that process
+    let capture = Capture::start(config.test_threads() == 1 || config.child_test.is_some());
+    let output = capture.for_thread();
+    let (sender, receiver) = mpsc::channel();
+    sensor.start_measuring();
+    thread::Builder::new()
+        .name(name.clone())
+        .spawn(move || {
+            output.install();
+            let cpu_start = thread_cpu_time();
+            let state = run_iterations(function, &thread_options, should_panic, &sender);
+            let cpu_time = thread_cpu_time()
+                .zip(cpu_start)
+                .map(|(end, start)| end - start);
+            ThreadCapture::uninstall();
+            // The runner is not listening anymore if the test timed out
+            let _ = sender.send(Progress::Finished {
+                state,
+                cpu_time,
+                budget_uj: budget::take_budget(),
+            });
+        })
+        .unwrap();
+
+    let mut iterations = Vec::new();
+    let mut cpu_time = None;
+    let mut warned = false;
+    let start = Instant::now();
+    let state = loop {
+        // Wake up when the test should be warned about or when it times out
+        let wait_until = match (timeout, warned) {
+            (Some(timeout), false) => Some(timeout.min(TEST_WARN_TIMEOUT)),
+            (Some(timeout), true) => Some(timeout),
+            (None, false) => Some(TEST_WARN_TIMEOUT),
+            (None, true) => None,
+        };
+        let progress = match wait_until {
+            Some(wait_until) => receiver.recv_timeout(wait_until.saturating_sub(start.elapsed())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match progress {
+            Ok(Progress::Iteration(iteration)) => iterations.push(iteration),
+            Ok(Progress::Finished {
+                state,
+                cpu_time: cpu,
+                budget_uj,
+            }) => {
+                cpu_time = cpu;
+                options.budget_uj = budget_uj.or(options.budget_uj);
+                break state;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                    // The thread of the test is abandoned, it keeps running in the background
+                    sensor.stop_measuring();
+                    iterations.push(Iteration {
+                        uj: sensor
+                            .get_measured_uj()
+                            .saturating_sub(iterations.iter().map(|i| i.uj + i.excluded_uj).sum()),
+                        us: sensor
+                            .get_elapsed_time_us()
+                            .saturating_sub(iterations.iter().map(|i| i.us + i.excluded_us).sum()),
+                        ..Default::default()
+                    });
+                    break TestResult::TimedOut;
+                }
+                // The output of the runner is not part of the output of the test
+                if !warned {
+                    warned = capture
+                        .uncaptured(|| warn_long_running(&name, start.elapsed(), config.format));
                 }
-                state
             }
-            _ => unimplemented!("Only StaticTestFns are supported right now"),
-        };
+            Err(RecvTimeoutError::Disconnected) => {
+                break TestResult::Failed(Some(
+                    "the thread of the test stopped unexpectedly".to_string(),
+                ))
+            }
+        }
+    };
+
+    let output = capture.finish();
+    let stdout = match state {
+        TestResult::Failed(_) | TestResult::TimedOut => Some(output),
+        _ => None,
+    };
 
-        // Reset the output capturing to the default behavior and transform the captured output
-        // to a vector of bytes.
-        io::set_output_capture(None);
-        let stdout = Some(data.lock().unwrap_or_else(|e| e.into_inner()).to_vec());
+    let (uj, us, counted_repeats, outliers) = aggregate(&mut iterations, &config.outliers);
+    let regions = aggregate_regions(&iterations, &config.outliers);
 
-        CompletedTest {
-            name: test.desc.name.to_string(),
-            state,
-            uj: Some(uj),
-            us: Some(us),
-            stdout,
+    CompletedTest {
+        state,
+        // Only time is measured with the fallback sensor, so there is no energy to check
+        uj: sensor.measures_energy().then_some(uj),
+        us: Some(us),
+        counted_repeats,
+        outliers,
+        iterations,
+        regions,
+        cpu_us: cpu_time.map(|cpu_time| cpu_time.as_micros()),
+        window: Some((start, Instant::now())),
+        stdout,
+        options,
+        ..CompletedTest::empty(name)
+    }
+}
+
+// Warns about a test that has been running for a long time. Returns whether it warned.
+fn warn_long_running(name: &str, elapsed: Duration, format: OutputFormat) -> bool {
+    if elapsed < TEST_WARN_TIMEOUT {
+        return false;
+    }
+    formatter(format)
+        .write_long_running(name, TEST_WARN_TIMEOUT)
+        .unwrap();
+    true
+}
+
+// Run all iterations of a test and report every iteration back to the runner
+fn run_iterations(
+    function: TestFunction,
+    options: &TestOptions,
+    should_panic: ShouldPanic,
+    progress: &Sender<Progress>,
+) -> TestResult {
+    match function {
+        TestFunction::Repeatable(f) => repeat(options, f, should_panic, progress),
+        TestFunction::Once(f) => {
+            let mut f = Some(f);
+            repeat(options, || f.take().unwrap()(), should_panic, progress)
+        }
+    }
+}
+
+// Repeat a test function a number of times in a row, after the warmup iterations. Stops at the
+// first iteration that does not pass.
+fn repeat(
+    options: &TestOptions,
+    mut f: impl FnMut() -> Result<(), String>,
+    should_panic: ShouldPanic,
+    progress: &Sender<Progress>,
+) -> TestResult {
+    let mut sensor = default_sensor();
+
+    let mut state = TestResult::Ignored;
+    for i in 0..options.warmup + options.repeats {
+        region::start_recording();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            sensor.start_measuring();
+            f()
+        }));
+        sensor.stop_measuring();
+        let recording = region::stop_recording();
+        let (uj, us) = if options.regions_only {
+            (recording.top_level_uj, recording.top_level_us)
+        } else {
+            (
+                sensor
+                    .get_measured_uj()
+                    .saturating_sub(recording.excluded_uj),
+                sensor
+                    .get_elapsed_time_us()
+                    .saturating_sub(recording.excluded_us),
+            )
+        };
+        let iteration = Iteration {
+            uj,
+            us,
+            outlier: false,
+            regions: recording.regions,
+            excluded_uj: recording.excluded_uj,
+            excluded_us: recording.excluded_us,
+        };
+        // The runner is not listening anymore if the test timed out
+        if i >= options.warmup && progress.send(Progress::Iteration(iteration)).is_err() {
+            break;
+        }
+
+        state = match result {
+            // Tests that return an `Err` have failed, regardless of `should_panic`
+            Ok(Err(msg)) => TestResult::Failed(Some(msg)),
+            result => test_state(should_panic, result.map(|_| ())),
+        };
+        if state != TestResult::Passed {
+            break;
         }
     }
+    state
+}
+
+// Flag the outliers among the iterations and sum the energy and time of the iterations that
+// should be counted. Returns the summed energy, time, amount of counted iterations and the
+// amount of outliers.
+fn aggregate(iterations: &mut [Iteration], config: &OutlierConfig) -> (u128, u128, usize, usize) {
+    let energy: Vec<u128> = iterations.iter().map(|i| i.uj).collect();
+    for (iteration, outlier) in iterations.iter_mut().zip(detect_outliers(&energy, config)) {
+        iteration.outlier = outlier;
+    }
+
+    let counted: Vec<_> = iterations
+        .iter()
+        .filter(|i| !(config.exclude && i.outlier))
+        .collect();
+    let uj = counted.iter().map(|i| i.uj).sum();
+    let us = counted.iter().map(|i| i.us).sum();
+    let outliers = iterations.iter().filter(|i| i.outlier).count();
+    (uj, us, counted.len(), outliers)
 }
 
-fn test_state(desc: &test::TestDesc, result: Result<(), Box<dyn Any + Send>>) -> TestResult {
-    use test::ShouldPanic;
+// Sum the energy and time of every region over the iterations that are counted
+fn aggregate_regions(iterations: &[Iteration], config: &OutlierConfig) -> Vec<RegionMeasurement> {
+    let mut regions = Vec::new();
+    for iteration in iterations.iter().filter(|i| !(config.exclude && i.outlier)) {
+        for region in &iteration.regions {
+            add_region(
+                &mut regions,
+                region.name.clone(),
+                region.uj,
+                region.us,
+                region.calls,
+            );
+        }
+    }
+    regions
+}
 
-    let result = match (desc.should_panic, result) {
+fn test_state(should_panic: ShouldPanic, result: Result<(), Box<dyn Any + Send>>) -> TestResult {
+    let result = match (should_panic, result) {
         (ShouldPanic::No, Ok(())) | (ShouldPanic::Yes, Err(_)) => TestResult::Passed,
         (ShouldPanic::YesWithMessage(msg), Err(ref err)) => {
             let maybe_panic_str = err
@@ -268,19 +932,6 @@ fn test_state(desc: &test::TestDesc, result: Result<(), Box<dyn Any + Send>>) ->
 mod tests {
     use super::*;
     use std::panic;
-    use test::TestDesc;
-
-    fn default_test_desc() -> TestDesc {
-        TestDesc {
-            name: test::StaticTestName("Test"),
-            ignore: false,
-            ignore_message: None,
-            should_panic: test::ShouldPanic::No,
-            compile_fail: false,
-            no_run: false,
-            test_type: test::TestType::UnitTest,
-        }
-    }
 
     fn generate_panic_info(message: &'static str) -> Box<dyn Any + Send> {
         catch_unwind(|| {
@@ -291,17 +942,15 @@ mod tests {
 
     #[test]
     fn test_succeeded_succeeds_without_panic() {
-        let desc = default_test_desc();
         let result = Ok(());
-        assert_eq!(test_state(&desc, result), TestResult::Passed)
+        assert_eq!(test_state(ShouldPanic::No, result), TestResult::Passed)
     }
 
     #[test]
     fn test_succeeded_unexpected_panic() {
-        let desc = default_test_desc();
         let panic_str = "Assertion failed";
         let result = Err(generate_panic_info(panic_str));
-        let test_result = test_state(&desc, result);
+        let test_result = test_state(ShouldPanic::No, result);
         if TestResult::Failed(None) != test_result {
             panic!("Result was {:?}", test_result)
         }
@@ -309,19 +958,17 @@ mod tests {
 
     #[test]
     fn test_succeeded_expected_panic_and_did_panic() {
-        let mut desc = default_test_desc();
-        desc.should_panic = test::ShouldPanic::Yes;
+        let should_panic = ShouldPanic::Yes;
         let result = Err(generate_panic_info("Assertion failed"));
-        let test_result = test_state(&desc, result);
+        let test_result = test_state(should_panic, result);
         assert_eq!(test_result, TestResult::Passed)
     }
 
     #[test]
     fn test_succeeded_expected_panic_but_did_not_panic() {
-        let mut desc = default_test_desc();
-        desc.should_panic = test::ShouldPanic::Yes;
+        let should_panic = ShouldPanic::Yes;
         let result = Ok(());
-        let test_result = test_state(&desc, result);
+        let test_result = test_state(should_panic, result);
         match test_result {
             TestResult::Failed(Some(msg)) => assert!(msg.contains("test did not panic")),
             _ => panic!("Result was {:?}", test_result),
@@ -330,32 +977,29 @@ mod tests {
 
     #[test]
     fn test_succeeded_expected_panic_with_str_message() {
-        let mut desc = default_test_desc();
-        desc.should_panic = test::ShouldPanic::YesWithMessage("This is a message");
+        let should_panic = ShouldPanic::YesWithMessage("This is a message");
         let result = Err(generate_panic_info("This is a message"));
-        assert_eq!(test_state(&desc, result), TestResult::Passed)
+        assert_eq!(test_state(should_panic, result), TestResult::Passed)
     }
 
     #[test]
     fn test_succeeded_expected_panic_with_string_message() {
-        let mut desc = default_test_desc();
-        desc.should_panic = test::ShouldPanic::YesWithMessage("This is a message");
+        let should_panic = ShouldPanic::YesWithMessage("This is a message");
         let result = Err(catch_unwind(|| {
             panic::panic_any(String::from("This is a message"));
         })
         .unwrap_err());
-        assert_eq!(test_state(&desc, result), TestResult::Passed)
+        assert_eq!(test_state(should_panic, result), TestResult::Passed)
     }
 
     #[test]
     fn test_succeeded_expected_panic_with_string_message_but_got_no_string() {
-        let mut desc = default_test_desc();
-        desc.should_panic = test::ShouldPanic::YesWithMessage("This is a message");
+        let should_panic = ShouldPanic::YesWithMessage("This is a message");
         let result = Err(catch_unwind(|| {
             panic::panic_any(123);
         })
         .unwrap_err());
-        let test_result = test_state(&desc, result);
+        let test_result = test_state(should_panic, result);
         match test_result {
             TestResult::Failed(Some(msg)) => {
                 assert!(msg.contains("expected panic with string value"))
@@ -366,10 +1010,9 @@ mod tests {
 
     #[test]
     fn test_succeeded_expected_panic_with_wrong_message() {
-        let mut desc = default_test_desc();
-        desc.should_panic = test::ShouldPanic::YesWithMessage("This is a message");
+        let should_panic = ShouldPanic::YesWithMessage("This is a message");
         let result = Err(generate_panic_info("This is another message"));
-        let test_result = test_state(&desc, result);
+        let test_result = test_state(should_panic, result);
         match test_result {
             TestResult::Failed(Some(msg)) => {
                 assert!(msg.contains("panic did not contain expected string"))
@@ -380,10 +1023,9 @@ mod tests {
 
     #[test]
     fn test_succeeded_expected_panic_with_message_but_with_no_message() {
-        let mut desc = default_test_desc();
-        desc.should_panic = test::ShouldPanic::YesWithMessage("This is a message");
+        let should_panic = ShouldPanic::YesWithMessage("This is a message");
         let result = Err(generate_panic_info(""));
-        let test_result = test_state(&desc, result);
+        let test_result = test_state(should_panic, result);
         match test_result {
             TestResult::Failed(Some(msg)) => {
                 assert!(msg.contains("panic did not contain expected string"))
@@ -394,10 +1036,9 @@ mod tests {
 
     #[test]
     fn test_succeeded_expected_panic_with_message_but_did_not_panic() {
-        let mut desc = default_test_desc();
-        desc.should_panic = test::ShouldPanic::YesWithMessage("This is a message");
+        let should_panic = ShouldPanic::YesWithMessage("This is a message");
         let result = Ok(());
-        let test_result = test_state(&desc, result);
+        let test_result = test_state(should_panic, result);
         match test_result {
             TestResult::Failed(Some(msg)) => {
                 assert!(msg.contains("test did not panic as expected"))
@@ -405,4 +1046,119 @@ mod tests {
             _ => panic!("Result is {:?}", test_result),
         }
     }
+
+    inventory::submit! {
+        TestSettings {
+            module_path: module_path!(),
+            name: "dynamic_test_with_warmup",
+            repeats: None,
+            warmup: Some(2),
+            budget_uj: None,
+            group: None,
+            ignore: false,
+            should_panic: ShouldPanic::No,
+            function: || Ok(()),
+        }
+    }
+
+    #[test]
+    fn dynamic_test_skips_warmup() {
+        let test = TestDefinition {
+            name: "test_runner::tests::dynamic_test_with_warmup".to_string(),
+            ignore: false,
+            should_panic: ShouldPanic::No,
+            kind: TestKind::Test,
+            function: TestFunction::Once(Box::new(|| Ok(()))),
+        };
+        // More than one test thread, so the output of the other tests is not captured
+        let config = Config {
+            test_threads: Some(2),
+            ..Config::default()
+        };
+        let result = run_test(test, &config);
+        assert_eq!(result.state, TestResult::Passed);
+        assert_eq!(result.options.warmup, 0);
+        assert_eq!(result.iterations.len(), 1);
+    }
+
+    #[test]
+    fn energy_is_none_without_energy_sensor() {
+        let test = TestDefinition {
+            name: "test_runner::tests::test_without_energy".to_string(),
+            ignore: false,
+            should_panic: ShouldPanic::No,
+            kind: TestKind::Test,
+            function: TestFunction::Once(Box::new(|| {
+                budget::set_budget(0);
+                Ok(())
+            })),
+        };
+        let config = Config {
+            test_threads: Some(2),
+            ..Config::default()
+        };
+        let result = run_test(test, &config);
+        if energy_unavailable_reason().is_some() {
+            assert_eq!(result.uj, None);
+            // A budget cannot be exceeded if the energy is not measured
+            assert_eq!(result.state, TestResult::Passed);
+        } else {
+            assert!(result.uj.is_some());
+        }
+    }
+
+    fn iterations(energy: &[u128]) -> Vec<Iteration> {
+        energy
+            .iter()
+            .map(|uj| Iteration {
+                uj: *uj,
+                us: 10,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn aggregate_includes_outliers_by_default() {
+        let mut iterations = iterations(&[100, 101, 99, 100, 1000]);
+        let config = OutlierConfig::default();
+        assert_eq!(aggregate(&mut iterations, &config), (1400, 50, 5, 1));
+        assert!(iterations[4].outlier);
+    }
+
+    #[test]
+    fn aggregate_excludes_outliers() {
+        let mut iterations = iterations(&[100, 101, 99, 100, 1000]);
+        let config = OutlierConfig {
+            exclude: true,
+            ..Default::default()
+        };
+        assert_eq!(aggregate(&mut iterations, &config), (400, 40, 4, 1));
+        assert_eq!(iterations.len(), 5);
+    }
+
+    #[test]
+    fn aggregate_regions_of_counted_iterations() {
+        let region = |uj| RegionMeasurement {
+            name: "parse".to_string(),
+            uj,
+            us: 1,
+            calls: 1,
+        };
+        let mut iterations = iterations(&[100, 101, 1000]);
+        iterations[0].regions = vec![region(10)];
+        iterations[1].regions = vec![region(20)];
+        iterations[2].regions = vec![region(30)];
+        iterations[2].outlier = true;
+
+        let regions = aggregate_regions(&iterations, &OutlierConfig::default());
+        assert_eq!(regions[0].uj, 60);
+        assert_eq!(regions[0].calls, 3);
+
+        let config = OutlierConfig {
+            exclude: true,
+            ..Default::default()
+        };
+        assert_eq!(aggregate_regions(&iterations, &config)[0].uj, 30);
+    }
 }
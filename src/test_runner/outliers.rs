@@ -0,0 +1,130 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::config::{OutlierConfig, OutlierMethod};
+
+// Flag the values that are outliers according to the configured method. The returned vector
+// has the same length as `values`, where `true` means that the value at that index is an
+// outlier.
+pub(crate) fn detect_outliers(values: &[u128], config: &OutlierConfig) -> Vec<bool> {
+    // There is not enough data to say anything meaningful about the spread of fewer values
+    if values.len() < 4 {
+        return vec![false; values.len()];
+    }
+
+    let values: Vec<f64> = values.iter().map(|v| *v as f64).collect();
+    match config.method {
+        OutlierMethod::None => vec![false; values.len()],
+        OutlierMethod::Tukey => tukey_fences(&values, config.tukey_k),
+        OutlierMethod::Mad => median_absolute_deviation(&values, config.mad_threshold),
+    }
+}
+
+// Values outside of [Q1 - k * IQR, Q3 + k * IQR] are outliers
+fn tukey_fences(values: &[f64], k: f64) -> Vec<bool> {
+    let sorted = sorted(values);
+    let q1 = quantile(&sorted, 0.25);
+    let q3 = quantile(&sorted, 0.75);
+    let iqr = q3 - q1;
+    let (lower, upper) = (q1 - k * iqr, q3 + k * iqr);
+    values.iter().map(|v| *v < lower || *v > upper).collect()
+}
+
+// Values with a modified z-score (Iglewicz and Hoaglin) above the threshold are outliers
+fn median_absolute_deviation(values: &[f64], threshold: f64) -> Vec<bool> {
+    let median = quantile(&sorted(values), 0.5);
+    let deviations: Vec<f64> = values.iter().map(|v| (v - median).abs()).collect();
+    let mad = quantile(&sorted(&deviations), 0.5);
+
+    // More than half of the values are identical, so everything that deviates from them is
+    // infinitely far away in terms of the MAD.
+    if mad == 0.0 {
+        return deviations.iter().map(|d| *d > 0.0).collect();
+    }
+    deviations
+        .iter()
+        .map(|d| 0.6745 * d / mad > threshold)
+        .collect()
+}
+
+pub(super) fn sorted(values: &[f64]) -> Vec<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    sorted
+}
+
+// Quantile of sorted values with linear interpolation between the closest ranks
+pub(super) fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let position = q * (sorted.len() - 1) as f64;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    let fraction = position - lower as f64;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * fraction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(method: OutlierMethod) -> OutlierConfig {
+        OutlierConfig {
+            method,
+            ..Default::default()
+        }
+    }
+
+    const WITH_SPIKE: [u128; 8] = [100, 102, 98, 101, 1000, 99, 100, 103];
+
+    #[test]
+    fn outliers_quantile_interpolates() {
+        assert_eq!(quantile(&[1.0, 2.0, 3.0, 4.0], 0.5), 2.5);
+        assert_eq!(quantile(&[1.0, 2.0, 3.0, 4.0, 5.0], 0.25), 2.0);
+    }
+
+    #[test]
+    fn outliers_none_flags_nothing() {
+        let flagged = detect_outliers(&WITH_SPIKE, &config(OutlierMethod::None));
+        assert!(flagged.iter().all(|f| !f));
+    }
+
+    #[test]
+    fn outliers_tukey_flags_spike() {
+        let flagged = detect_outliers(&WITH_SPIKE, &config(OutlierMethod::Tukey));
+        assert_eq!(
+            flagged,
+            vec![false, false, false, false, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn outliers_mad_flags_spike() {
+        let flagged = detect_outliers(&WITH_SPIKE, &config(OutlierMethod::Mad));
+        assert_eq!(
+            flagged,
+            vec![false, false, false, false, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn outliers_mad_identical_values() {
+        let flagged = detect_outliers(&[5, 5, 5, 5, 5, 50], &config(OutlierMethod::Mad));
+        assert_eq!(flagged, vec![false, false, false, false, false, true]);
+    }
+
+    #[test]
+    fn outliers_too_few_values() {
+        let flagged = detect_outliers(&[1, 1000, 1], &config(OutlierMethod::Tukey));
+        assert_eq!(flagged, vec![false, false, false]);
+    }
+}
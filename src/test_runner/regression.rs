@@ -0,0 +1,301 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Compares the energy of every test with the energy of the same test in the previous runs on
+// the same host. The iterations of a test in the previous runs are pooled together, and a test
+// has regressed if its iterations consume significantly more energy than those.
+
+use super::json::JsonResult;
+use super::outliers::{quantile, sorted};
+use super::{CompletedTest, TestResult};
+use crate::config::{RegressionConfig, RegressionMethod};
+
+// There is not enough data to say anything meaningful about fewer iterations
+const MINIMUM_ITERATIONS: usize = 3;
+
+// The z-score of a two-sided 95% confidence interval
+const CONFIDENCE_Z: f64 = 1.96;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub(super) struct Regression {
+    pub(super) name: String,
+    // The test binary that the test is part of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) binary: Option<String>,
+    // Energy per iteration in the previous runs and in this run
+    pub(super) baseline_uj: f64,
+    pub(super) current_uj: f64,
+    // Increase of the energy per iteration, in percent
+    pub(super) increase: f64,
+    // Probability that the increase is a coincidence, only known for the Mann-Whitney U test
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) p_value: Option<f64>,
+}
+
+pub(super) fn find_regressions(
+    tests: &[CompletedTest],
+    history: &[JsonResult],
+    config: &RegressionConfig,
+) -> Vec<Regression> {
+    tests
+        .iter()
+        .filter(|test| comparable(test))
+        .filter_map(|test| {
+            let baseline: Vec<f64> = history
+                .iter()
+                .flat_map(|result| &result.tests)
+                .filter(|old| {
+                    old.name == test.name
+                        && old.measurement == test.measurement
+                        && comparable(old)
+                        // Older results do not know which binary a test is part of
+                        && (old.binary.is_none() || old.binary == test.binary)
+                })
+                .flat_map(iterations)
+                .collect();
+            let regression = compare(&test.name, &iterations(test), &baseline, config)?;
+            Some(Regression {
+                binary: test.binary.clone(),
+                ..regression
+            })
+        })
+        .collect()
+}
+
+// Only the tests that ran completely have a meaningful energy consumption
+fn comparable(test: &CompletedTest) -> bool {
+    matches!(test.state, TestResult::Passed | TestResult::OverBudget)
+}
+
+// Outliers are left out of the comparison, since they are most likely caused by something else
+// than the test itself
+fn iterations(test: &CompletedTest) -> Vec<f64> {
+    test.iterations
+        .iter()
+        .filter(|iteration| !iteration.outlier)
+        .map(|iteration| iteration.uj as f64)
+        .collect()
+}
+
+fn compare(
+    name: &str,
+    current: &[f64],
+    baseline: &[f64],
+    config: &RegressionConfig,
+) -> Option<Regression> {
+    if current.len() < MINIMUM_ITERATIONS || baseline.len() < MINIMUM_ITERATIONS {
+        return None;
+    }
+
+    let (baseline_uj, current_uj, p_value, significant) = match config.method {
+        RegressionMethod::MannWhitney => {
+            let p_value = mann_whitney_p_value(current, baseline);
+            (
+                median(baseline),
+                median(current),
+                Some(p_value),
+                p_value < config.significance,
+            )
+        }
+        RegressionMethod::Threshold => {
+            let (current_lower, _) = confidence_interval(current);
+            let (_, baseline_upper) = confidence_interval(baseline);
+            (
+                mean(baseline),
+                mean(current),
+                None,
+                current_lower > baseline_upper,
+            )
+        }
+    };
+
+    // The energy of the test was too small for the sensor to measure in the previous runs, so
+    // the increase cannot be expressed relative to it
+    if baseline_uj == 0.0 {
+        return None;
+    }
+    let increase = (current_uj - baseline_uj) / baseline_uj * 100.0;
+
+    (significant && increase >= config.threshold).then(|| Regression {
+        name: name.to_string(),
+        binary: None,
+        baseline_uj,
+        current_uj,
+        increase,
+        p_value,
+    })
+}
+
+// One-sided Mann-Whitney U test of whether the values of `current` tend to be larger than the
+// values of `baseline`. Uses the normal approximation, with a correction for ties.
+fn mann_whitney_p_value(current: &[f64], baseline: &[f64]) -> f64 {
+    let (n1, n2) = (current.len() as f64, baseline.len() as f64);
+    let n = n1 + n2;
+
+    let mut values: Vec<(f64, bool)> = current
+        .iter()
+        .map(|v| (*v, true))
+        .chain(baseline.iter().map(|v| (*v, false)))
+        .collect();
+    values.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // Tied values all get the average of their ranks
+    let mut rank_sum = 0.0;
+    let mut ties = 0.0;
+    let mut i = 0;
+    while i < values.len() {
+        let mut j = i;
+        while j < values.len() && values[j].0 == values[i].0 {
+            j += 1;
+        }
+        let rank = (i + j + 1) as f64 / 2.0;
+        rank_sum += rank * values[i..j].iter().filter(|(_, current)| *current).count() as f64;
+        let t = (j - i) as f64;
+        ties += t * t * t - t;
+        i = j;
+    }
+
+    let u = rank_sum - n1 * (n1 + 1.0) / 2.0;
+    let mean = n1 * n2 / 2.0;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    if variance <= 0.0 {
+        // All values are the same
+        return 1.0;
+    }
+    let z = (u - mean - 0.5) / variance.sqrt();
+    1.0 - standard_normal_cdf(z)
+}
+
+fn standard_normal_cdf(z: f64) -> f64 {
+    0.5 * (1.0 + erf(z / std::f64::consts::SQRT_2))
+}
+
+// Approximation of the error function by Abramowitz and Stegun (7.1.26), with a maximum error
+// of 1.5e-7
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let polynomial = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let result = 1.0 - polynomial * (-x * x).exp();
+    if x < 0.0 {
+        -result
+    } else {
+        result
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn median(values: &[f64]) -> f64 {
+    quantile(&sorted(values), 0.5)
+}
+
+// 95% confidence interval of the mean
+fn confidence_interval(values: &[f64]) -> (f64, f64) {
+    let mean = mean(values);
+    let variance =
+        values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+    let margin = CONFIDENCE_Z * variance.sqrt() / (values.len() as f64).sqrt();
+    (mean - margin, mean + margin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(method: RegressionMethod) -> RegressionConfig {
+        RegressionConfig {
+            method,
+            ..RegressionConfig::default()
+        }
+    }
+
+    #[test]
+    fn mann_whitney_detects_increase() {
+        let baseline = [100.0, 102.0, 98.0, 101.0, 99.0, 100.0, 103.0, 97.0];
+        let current = [120.0, 118.0, 122.0, 119.0, 121.0, 117.0, 123.0, 120.0];
+        assert!(mann_whitney_p_value(&current, &baseline) < 0.001);
+        assert!(mann_whitney_p_value(&baseline, &current) > 0.999);
+
+        let regression = compare(
+            "test",
+            &current,
+            &baseline,
+            &config(RegressionMethod::MannWhitney),
+        )
+        .unwrap();
+        assert_eq!(regression.baseline_uj, 100.0);
+        assert_eq!(regression.current_uj, 120.0);
+        assert_eq!(regression.increase, 20.0);
+    }
+
+    #[test]
+    fn mann_whitney_identical_values() {
+        let values = [100.0; 5];
+        assert_eq!(mann_whitney_p_value(&values, &values), 1.0);
+    }
+
+    #[test]
+    fn no_regression_without_significance() {
+        let baseline = [100.0, 130.0, 90.0, 120.0, 95.0];
+        let current = [105.0, 125.0, 92.0, 128.0, 99.0];
+        for method in [RegressionMethod::MannWhitney, RegressionMethod::Threshold] {
+            assert_eq!(compare("test", &current, &baseline, &config(method)), None);
+        }
+    }
+
+    #[test]
+    fn no_regression_below_threshold() {
+        let baseline = [100.0, 100.0, 101.0, 100.0, 99.0];
+        let current = [103.0, 103.0, 104.0, 103.0, 102.0];
+        for method in [RegressionMethod::MannWhitney, RegressionMethod::Threshold] {
+            assert_eq!(compare("test", &current, &baseline, &config(method)), None);
+        }
+    }
+
+    #[test]
+    fn threshold_detects_increase() {
+        let baseline = [100.0, 100.0, 101.0, 100.0, 99.0];
+        let current = [110.0, 110.0, 111.0, 110.0, 109.0];
+        let regression = compare(
+            "test",
+            &current,
+            &baseline,
+            &config(RegressionMethod::Threshold),
+        )
+        .unwrap();
+        assert_eq!(regression.increase, 10.0);
+        assert_eq!(regression.p_value, None);
+    }
+
+    #[test]
+    fn no_regression_with_few_iterations() {
+        let config = config(RegressionMethod::MannWhitney);
+        assert_eq!(compare("test", &[200.0], &[100.0; 10], &config), None);
+        assert_eq!(
+            compare("test", &[200.0; 10], &[100.0, 100.0], &config),
+            None
+        );
+    }
+
+    #[test]
+    fn erf_approximation() {
+        assert!(erf(0.0).abs() < 1e-7);
+        assert!((erf(1.0) - 0.8427007929).abs() < 1e-6);
+        assert!((erf(-1.0) + 0.8427007929).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Every run of a test binary gets a unique ID, so runs never overwrite each other's results.
+// The runs of the test binaries that are started by the same `cargo test` or `cargo coppers
+// test` share an invocation ID, which identifies the process that started them. The results
+// directory has an index with a line per run.
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::time::SystemTime;
+
+// Overrides the invocation ID, for example to group the runs by the ID of a CI job
+pub(crate) const INVOCATION_VARIABLE: &str = "COPPERS_INVOCATION_ID";
+
+pub(super) const INDEX_FILE_NAME: &str = "index.jsonl";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub(super) struct IndexEntry {
+    pub(super) run_id: String,
+    pub(super) invocation_id: String,
+    pub(super) execution_timestamp: u64,
+    // Name of the results file within the results directory
+    pub(super) file: String,
+}
+
+// The milliseconds since the epoch, the process ID and the name of the binary
+pub(crate) fn new_run_id(binary: &str) -> String {
+    let millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    format!("{millis}-{}-{binary}", process::id())
+}
+
+// The invocation ID of a test binary, which is the process that started it
+pub(super) fn invocation_id() -> String {
+    env::var(INVOCATION_VARIABLE).unwrap_or_else(|_| process_identity(parent_id()))
+}
+
+// The invocation ID of the test binaries that are started by the current process
+pub(crate) fn own_invocation_id() -> String {
+    env::var(INVOCATION_VARIABLE).unwrap_or_else(|_| process_identity(process::id()))
+}
+
+// Process IDs are reused, so the start time of the process is included when it is known
+fn process_identity(pid: u32) -> String {
+    match start_time(pid) {
+        Some(start_time) => format!("{pid}-{start_time}"),
+        None => pid.to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn parent_id() -> u32 {
+    std::os::unix::process::parent_id()
+}
+
+#[cfg(not(unix))]
+fn parent_id() -> u32 {
+    0
+}
+
+// The start time of a process in clock ticks since boot, which is the 22nd field of its stat file
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    parse_start_time(&stat)
+}
+
+fn parse_start_time(stat: &str) -> Option<u64> {
+    // The name of the process is the second field, and can contain spaces and parentheses
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+pub(super) fn append_to_index(directory: &Path, entry: &IndexEntry) {
+    let mut line = serde_json::to_string(entry).unwrap();
+    line.push('\n');
+    // A single write to a file that is opened to append is not interleaved with other writes,
+    // so runs that finish at the same time do not corrupt the index
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(directory.join(INDEX_FILE_NAME))
+        .unwrap();
+    file.write_all(line.as_bytes()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_ids_are_unique() {
+        let first = new_run_id("integration");
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let second = new_run_id("integration");
+        assert_ne!(first, second);
+        assert!(first.ends_with(&format!("-{}-integration", process::id())));
+    }
+
+    #[test]
+    fn start_time_from_stat() {
+        let stat = "1234 (my (test) binary) S 1 1234 1234 0 -1 4194560 105 0 0 0 0 0 0 0 20 0 1 0 5678 1000 100";
+        assert_eq!(parse_start_time(stat), Some(5678));
+        assert_eq!(parse_start_time("1234 (truncated"), None);
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Every result records the version of the format it is written in, which is described by the
+// JSON Schema in `schema/coppers_results.schema.json`. Results in an older format are upgraded
+// when they are read, one version at a time. Results without a version are from before the
+// format was versioned, which is version 0.
+
+use serde_json::{json, Map, Value};
+
+// The version of the format that results are written in. Every change to the format increases
+// it, with a migration from the previous version and an update of the JSON Schema.
+pub(crate) const SCHEMA_VERSION: u64 = 2;
+
+// The migration at index `i` upgrades a result from version `i` to version `i + 1`
+const MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[unversioned_to_1, version_1_to_2];
+
+// Upgrade a result to the current version of the format
+pub(super) fn upgrade(mut result: Value) -> Result<Value, String> {
+    let object = result
+        .as_object_mut()
+        .ok_or("A result has to be a JSON object")?;
+    let version = object
+        .get("schema_version")
+        .map_or(Some(0), Value::as_u64)
+        .ok_or("The schema version of a result has to be a number")?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "The result is written in version {version} of the format, but this version of Coppers only reads up to version {SCHEMA_VERSION}"
+        ));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(object);
+    }
+    object.insert("schema_version".to_string(), json!(SCHEMA_VERSION));
+    Ok(result)
+}
+
+// The commit used to be stored at the top level, and so was the hostname before there was an
+// environment. Tests did not record their iterations yet.
+fn unversioned_to_1(result: &mut Map<String, Value>) {
+    if let Some(head) = result.remove("head") {
+        let commit_timestamp = result.remove("commit_timestamp");
+        result.entry("git").or_insert_with(|| {
+            json!({
+                "head": head,
+                "commit_timestamp": commit_timestamp,
+                "dirty": false,
+            })
+        });
+    }
+    if let Some(hostname) = result.remove("hostname") {
+        result
+            .entry("environment")
+            .or_insert_with(|| json!({ "hostname": hostname }));
+    }
+
+    let repeats = result.get("number_of_repeats").cloned().unwrap_or(json!(0));
+    let tests = result.get_mut("tests").and_then(Value::as_array_mut);
+    for test in tests.into_iter().flatten().filter_map(Value::as_object_mut) {
+        // All repeats are counted towards the energy of a test that ran
+        let counted = if test.get("uj").is_none_or(Value::is_null) {
+            json!(0)
+        } else {
+            repeats.clone()
+        };
+        test.entry("counted_repeats").or_insert(counted);
+        test.entry("outliers").or_insert(json!(0));
+        test.entry("iterations").or_insert(json!([]));
+    }
+}
+
+// The amount of repeats of a run was the default of every test, which tests can override. Every
+// test records how many of its iterations are counted instead.
+fn version_1_to_2(result: &mut Map<String, Value>) {
+    result.remove("number_of_repeats");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CompletedTest, JsonResult};
+    use super::*;
+
+    const SCHEMA: &str = include_str!("../../schema/coppers_results.schema.json");
+
+    #[test]
+    fn upgrade_unversioned_result() {
+        let result = json!({
+            "execution_timestamp": 1650000000,
+            "head": "4c9e1a0d6b3f8e2a7c5d9b1f0e3a6c8d2b4f7e9a",
+            "commit_timestamp": 1649999000,
+            "hostname": "build-server-3",
+            "total_us": 10,
+            "total_uj": 100,
+            "overhead_us": 1,
+            "overhead_uj": 1,
+            "number_of_repeats": 5,
+            "tests": [
+                { "name": "tests::a", "state": "Passed", "uj": 90, "us": 8 },
+                { "name": "tests::b", "state": "Ignored", "uj": null, "us": null }
+            ]
+        });
+        let upgraded = upgrade(result).unwrap();
+        assert_eq!(upgraded["schema_version"], SCHEMA_VERSION);
+        assert_eq!(upgraded["git"]["commit_timestamp"], 1649999000);
+        assert_eq!(upgraded["environment"]["hostname"], "build-server-3");
+        assert_eq!(upgraded["tests"][0]["counted_repeats"], 5);
+        assert_eq!(upgraded["tests"][1]["counted_repeats"], 0);
+        assert!(upgraded.get("number_of_repeats").is_none());
+
+        let result: JsonResult = serde_json::from_value(upgraded).unwrap();
+        assert_eq!(result.git.unwrap().head.unwrap().len(), 40);
+        assert_eq!(result.environment.hostname, "build-server-3");
+    }
+
+    #[test]
+    fn reject_newer_result() {
+        assert!(upgrade(json!({ "schema_version": SCHEMA_VERSION + 1 })).is_err());
+        assert!(upgrade(json!([])).is_err());
+    }
+
+    // The published schema describes the current version of the format
+    #[test]
+    fn schema_describes_results() {
+        let schema: Value = serde_json::from_str(SCHEMA).unwrap();
+        assert_eq!(
+            schema["properties"]["schema_version"]["const"],
+            SCHEMA_VERSION
+        );
+        let result = JsonResult {
+            tests: vec![CompletedTest::empty("tests::a".to_string())],
+            ..JsonResult::example(10, 100)
+        };
+        let result = serde_json::to_value(result).unwrap();
+        assert_described(&result, &schema);
+        assert_described(&result["tests"][0], &schema["$defs"]["test"]);
+    }
+
+    fn assert_described(value: &Value, schema: &Value) {
+        let properties = schema["properties"].as_object().unwrap();
+        for field in value.as_object().unwrap().keys() {
+            assert!(properties.contains_key(field), "{field} is not described");
+        }
+        for field in schema["required"].as_array().unwrap() {
+            assert!(value.get(field.as_str().unwrap()).is_some());
+        }
+    }
+}
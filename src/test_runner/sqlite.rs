@@ -0,0 +1,683 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A store that keeps all runs in a single SQLite database. The runs, their environments, their
+// tests and the iterations of those tests all have their own table, so they can be queried
+// without reading every run. Every test also keeps its complete results as JSON, from which the
+// runs are read back.
+
+use super::environment::Environment;
+use super::git::GitInfo;
+use super::json::JsonResult;
+use super::schema::SCHEMA_VERSION;
+use super::store::{Query, ResultStore, TestAggregate};
+use super::{CompletedTest, TestResult};
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+use std::fs::create_dir_all;
+use std::path::Path;
+use std::time::Duration;
+
+pub(crate) const DATABASE_FILE_NAME: &str = "coppers.sqlite";
+
+// Every migration upgrades the schema by one version. The version of a database is stored as
+// its `user_version`, so only the migrations that it is missing are applied to it. The second
+// migration merges the environments that are stored more than once, and makes sure they are
+// stored only once from then on. Missing values are compared as an empty blob in its index,
+// because SQLite considers every NULL to be unique. The third migration drops the amount of
+// repeats of a run, because every test records how many of its iterations are counted.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE environments (
+        id INTEGER PRIMARY KEY,
+        hostname TEXT NOT NULL,
+        cpu_model TEXT,
+        cores INTEGER,
+        kernel TEXT,
+        governor TEXT,
+        turbo INTEGER,
+        rustc TEXT NOT NULL,
+        target TEXT NOT NULL,
+        profile TEXT NOT NULL,
+        features TEXT NOT NULL,
+        sensor TEXT NOT NULL
+    );
+    CREATE TABLE runs (
+        id INTEGER PRIMARY KEY,
+        run_id TEXT NOT NULL UNIQUE,
+        invocation_id TEXT NOT NULL,
+        merged_runs TEXT NOT NULL,
+        execution_timestamp INTEGER NOT NULL,
+        environment INTEGER NOT NULL REFERENCES environments (id),
+        git_head TEXT,
+        git_commit_timestamp INTEGER,
+        git_branch TEXT,
+        git_dirty INTEGER,
+        git_diff_hash TEXT,
+        total_us INTEGER NOT NULL,
+        total_uj INTEGER NOT NULL,
+        overhead_us INTEGER NOT NULL,
+        overhead_uj INTEGER NOT NULL,
+        number_of_repeats INTEGER NOT NULL,
+        test_threads INTEGER NOT NULL,
+        outlier_detection TEXT NOT NULL,
+        regressions TEXT NOT NULL,
+        baseline_deviations TEXT NOT NULL
+    );
+    CREATE INDEX runs_by_execution ON runs (execution_timestamp);
+    CREATE TABLE tests (
+        id INTEGER PRIMARY KEY,
+        run INTEGER NOT NULL REFERENCES runs (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        binary TEXT,
+        name TEXT NOT NULL,
+        state TEXT NOT NULL,
+        uj INTEGER,
+        us INTEGER,
+        counted_repeats INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX tests_by_name ON tests (name);
+    CREATE TABLE iterations (
+        test INTEGER NOT NULL REFERENCES tests (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        uj INTEGER NOT NULL,
+        us INTEGER NOT NULL,
+        outlier INTEGER NOT NULL,
+        PRIMARY KEY (test, position)
+    );
+",
+    "
+    UPDATE runs SET environment = (
+        SELECT MIN(other.id) FROM environments AS other
+            JOIN environments AS own ON own.id = runs.environment
+            WHERE other.hostname = own.hostname AND other.cpu_model IS own.cpu_model
+            AND other.cores IS own.cores AND other.kernel IS own.kernel
+            AND other.governor IS own.governor AND other.turbo IS own.turbo
+            AND other.rustc = own.rustc AND other.target = own.target
+            AND other.profile = own.profile AND other.features = own.features
+            AND other.sensor = own.sensor
+    );
+    DELETE FROM environments WHERE id NOT IN (SELECT environment FROM runs);
+    CREATE UNIQUE INDEX environments_unique ON environments (hostname, IFNULL(cpu_model, x''),
+        IFNULL(cores, x''), IFNULL(kernel, x''), IFNULL(governor, x''), IFNULL(turbo, x''),
+        rustc, target, profile, features, sensor);
+",
+    "
+    ALTER TABLE runs DROP COLUMN number_of_repeats;
+",
+];
+
+// How long a process waits for another process that is writing to the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const RUN_COLUMNS: &str = "runs.id, run_id, invocation_id, merged_runs, execution_timestamp,
+    git_head, git_commit_timestamp, git_branch, git_dirty, git_diff_hash, total_us, total_uj,
+    overhead_us, overhead_uj, test_threads, outlier_detection, regressions, baseline_deviations,
+    hostname, cpu_model, cores, kernel, governor, turbo, rustc, target,
+    profile, features, sensor";
+
+pub(crate) struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    // Open the database in the results directory, and create or upgrade it when needed
+    pub(crate) fn open(directory: &Path) -> Result<SqliteStore, String> {
+        create_dir_all(directory).map_err(|e| e.to_string())?;
+        let connection =
+            Connection::open(directory.join(DATABASE_FILE_NAME)).map_err(|e| e.to_string())?;
+        SqliteStore::with_connection(connection)
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<SqliteStore, String> {
+        // Test binaries that run at the same time wait for each other
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .map_err(|e| e.to_string())?;
+        migrate(&mut connection).map_err(|e| format!("Could not upgrade the database: {e}"))?;
+        connection
+            .execute_batch("PRAGMA foreign_keys = ON")
+            .map_err(|e| e.to_string())?;
+        Ok(SqliteStore { connection })
+    }
+
+    // The ID of the environment that the query is limited to, if it is limited to one. Returns
+    // `None` if no run is executed in the environment of the query.
+    fn environment_of(&self, query: &Query) -> Result<Option<Option<i64>>, String> {
+        match query.environment {
+            Some(environment) => environment_id(&self.connection, environment)
+                .map(|id| id.map(Some))
+                .map_err(|e| e.to_string()),
+            None => Ok(Some(None)),
+        }
+    }
+
+    fn tests_of(&self, run: i64) -> Result<Vec<CompletedTest>, String> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT data FROM tests WHERE run = ?1 ORDER BY position")
+            .map_err(|e| e.to_string())?;
+        let tests = statement
+            .query_map([run], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?
+            .map(|data| {
+                serde_json::from_str(&data.map_err(|e| e.to_string())?).map_err(|e| e.to_string())
+            })
+            .collect();
+        tests
+    }
+}
+
+// The ID of the environment, if any run is stored with it
+fn environment_id(
+    connection: &Connection,
+    environment: &Environment,
+) -> rusqlite::Result<Option<i64>> {
+    connection
+        .query_row(
+            "SELECT id FROM environments WHERE hostname = ?1 AND cpu_model IS ?2
+                    AND cores IS ?3 AND kernel IS ?4 AND governor IS ?5 AND turbo IS ?6
+                    AND rustc = ?7 AND target = ?8 AND profile = ?9 AND features = ?10
+                    AND sensor = ?11",
+            params![
+                environment.hostname,
+                environment.cpu_model,
+                environment.cores,
+                environment.kernel,
+                environment.governor,
+                environment.turbo,
+                environment.rustc,
+                environment.target,
+                environment.profile,
+                to_json(&environment.features),
+                environment.sensor,
+            ],
+            |row| row.get(0),
+        )
+        .optional()
+}
+
+// The version is read within the transaction, so processes that open the database at the same
+// time do not apply the same migration twice
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: usize = transaction.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+    }
+    transaction.commit()
+}
+
+impl ResultStore for SqliteStore {
+    fn save(&mut self, result: &JsonResult) -> Result<(), String> {
+        // The transaction starts writing right away, so it waits for other writers up front
+        // instead of failing when it turns from reading into writing
+        let transaction = self
+            .connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|e| e.to_string())?;
+        insert_environment(&transaction, &result.environment).map_err(|e| e.to_string())?;
+        let environment = environment_id(&transaction, &result.environment)
+            .map_err(|e| e.to_string())?
+            .ok_or("The environment of the run is not stored")?;
+        insert_run(&transaction, result, environment).map_err(|e| e.to_string())?;
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    fn runs(&self, query: &Query) -> Result<Vec<JsonResult>, String> {
+        // No run is executed in an environment that is not stored
+        let Some(environment) = self.environment_of(query)? else {
+            return Ok(Vec::new());
+        };
+        let limit = limit_of(query);
+        let mut statement = self
+            .connection
+            .prepare(&format!(
+                "SELECT {RUN_COLUMNS} FROM runs
+                    JOIN environments ON environments.id = runs.environment
+                    WHERE ?1 IS NULL OR runs.environment = ?1
+                    ORDER BY execution_timestamp DESC, runs.id DESC LIMIT ?2"
+            ))
+            .map_err(|e| e.to_string())?;
+        let runs = statement
+            .query_map(params![environment, limit], |row| {
+                Ok((row.get::<_, i64>(0)?, run_from_row(row)?))
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(|e| e.to_string())?;
+        runs.into_iter()
+            .map(|(id, run)| {
+                Ok(JsonResult {
+                    tests: self.tests_of(id)?,
+                    ..run
+                })
+            })
+            .collect()
+    }
+
+    fn contains(&self, run_id: &str) -> Result<bool, String> {
+        self.connection
+            .query_row("SELECT 1 FROM runs WHERE run_id = ?1", [run_id], |_| Ok(()))
+            .optional()
+            .map(|row| row.is_some())
+            .map_err(|e| e.to_string())
+    }
+
+    // Only the columns of the tests are read, instead of their complete results
+    fn aggregates(&self, query: &Query) -> Result<Vec<TestAggregate>, String> {
+        let Some(environment) = self.environment_of(query)? else {
+            return Ok(Vec::new());
+        };
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT run_id, execution_timestamp, git_head, git_commit_timestamp, git_dirty,
+                    binary, name, uj, us, counted_repeats FROM tests
+                    JOIN runs ON runs.id = tests.run
+                    WHERE runs.id IN (SELECT id FROM runs WHERE ?1 IS NULL OR environment = ?1
+                        ORDER BY execution_timestamp DESC, id DESC LIMIT ?2)
+                    AND state IN ('Passed', 'OverBudget') AND counted_repeats > 0
+                    AND uj IS NOT NULL AND us IS NOT NULL
+                    ORDER BY execution_timestamp DESC, runs.id DESC, position",
+            )
+            .map_err(|e| e.to_string())?;
+        let aggregates = statement
+            .query_map(params![environment, limit_of(query)], |row| {
+                let counted_repeats = row.get::<_, i64>("counted_repeats")? as f64;
+                Ok(TestAggregate {
+                    run_id: row.get("run_id")?,
+                    execution_timestamp: row.get::<_, i64>("execution_timestamp")? as u64,
+                    head: row.get("git_head")?,
+                    commit_timestamp: row.get("git_commit_timestamp")?,
+                    dirty: row.get::<_, Option<bool>>("git_dirty")?.unwrap_or(false),
+                    binary: row.get("binary")?,
+                    name: row.get("name")?,
+                    uj: row.get::<_, i64>("uj")? as f64 / counted_repeats,
+                    us: row.get::<_, i64>("us")? as f64 / counted_repeats,
+                })
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(|e| e.to_string());
+        aggregates
+    }
+}
+
+// A negative limit means no limit at all
+fn limit_of(query: &Query) -> i64 {
+    query.limit.map_or(-1, |limit| limit as i64)
+}
+
+// Environments that are stored already are left alone
+fn insert_environment(connection: &Connection, environment: &Environment) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO environments (hostname, cpu_model, cores, kernel, governor, turbo, rustc,
+            target, profile, features, sensor)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+            ON CONFLICT DO NOTHING",
+        params![
+            environment.hostname,
+            environment.cpu_model,
+            environment.cores,
+            environment.kernel,
+            environment.governor,
+            environment.turbo,
+            environment.rustc,
+            environment.target,
+            environment.profile,
+            to_json(&environment.features),
+            environment.sensor,
+        ],
+    )?;
+    Ok(())
+}
+
+// Energy and time fit in 64 bits, which is what SQLite supports
+fn insert_run(
+    connection: &Connection,
+    result: &JsonResult,
+    environment: i64,
+) -> rusqlite::Result<()> {
+    let git = result.git.as_ref();
+    connection.execute(
+        "INSERT INTO runs (run_id, invocation_id, merged_runs, execution_timestamp, environment,
+            git_head, git_commit_timestamp, git_branch, git_dirty, git_diff_hash, total_us,
+            total_uj, overhead_us, overhead_uj, test_threads, outlier_detection, regressions,
+            baseline_deviations)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+            ?18)",
+        params![
+            result.run_id,
+            result.invocation_id,
+            to_json(&result.merged_runs),
+            result.execution_timestamp as i64,
+            environment,
+            git.and_then(|git| git.head.as_ref()),
+            git.and_then(|git| git.commit_timestamp),
+            git.and_then(|git| git.branch.as_ref()),
+            git.map(|git| git.dirty),
+            git.and_then(|git| git.diff_hash.as_ref()),
+            result.total_us as i64,
+            result.total_uj as i64,
+            result.overhead_us as i64,
+            result.overhead_uj as i64,
+            result.test_threads,
+            to_json(&result.outlier_detection),
+            to_json(&result.regressions),
+            to_json(&result.baseline_deviations),
+        ],
+    )?;
+    let run = connection.last_insert_rowid();
+    for (position, test) in result.tests.iter().enumerate() {
+        connection.execute(
+            "INSERT INTO tests (run, position, binary, name, state, uj, us, counted_repeats, data)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                run,
+                position,
+                test.binary,
+                test.name,
+                state_name(&test.state),
+                test.uj.map(|uj| uj as i64),
+                test.us.map(|us| us as i64),
+                test.counted_repeats,
+                to_json(test),
+            ],
+        )?;
+        let test_id = connection.last_insert_rowid();
+        for (position, iteration) in test.iterations.iter().enumerate() {
+            connection.execute(
+                "INSERT INTO iterations (test, position, uj, us, outlier)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    test_id,
+                    position,
+                    iteration.uj as i64,
+                    iteration.us as i64,
+                    iteration.outlier
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// The tests of the run are read separately
+fn run_from_row(row: &Row) -> rusqlite::Result<JsonResult> {
+    let git = match row.get::<_, Option<bool>>("git_dirty")? {
+        Some(dirty) => Some(GitInfo {
+            head: row.get("git_head")?,
+            commit_timestamp: row.get("git_commit_timestamp")?,
+            branch: row.get("git_branch")?,
+            dirty,
+            diff_hash: row.get("git_diff_hash")?,
+        }),
+        None => None,
+    };
+    Ok(JsonResult {
+        schema_version: SCHEMA_VERSION,
+        run_id: row.get("run_id")?,
+        invocation_id: row.get("invocation_id")?,
+        merged_runs: from_json(row, "merged_runs")?,
+        execution_timestamp: row.get::<_, i64>("execution_timestamp")? as u64,
+        environment: Environment {
+            hostname: row.get("hostname")?,
+            cpu_model: row.get("cpu_model")?,
+            cores: row.get("cores")?,
+            kernel: row.get("kernel")?,
+            governor: row.get("governor")?,
+            turbo: row.get("turbo")?,
+            rustc: row.get("rustc")?,
+            target: row.get("target")?,
+            profile: row.get("profile")?,
+            features: from_json(row, "features")?,
+            sensor: row.get("sensor")?,
+        },
+        git,
+        total_us: row.get::<_, i64>("total_us")? as u128,
+        total_uj: row.get::<_, i64>("total_uj")? as u128,
+        overhead_us: row.get::<_, i64>("overhead_us")? as u128,
+        overhead_uj: row.get::<_, i64>("overhead_uj")? as u128,
+        test_threads: row.get("test_threads")?,
+        outlier_detection: from_json(row, "outlier_detection")?,
+        tests: Vec::new(),
+        regressions: from_json(row, "regressions")?,
+        baseline_deviations: from_json(row, "baseline_deviations")?,
+    })
+}
+
+fn state_name(state: &TestResult) -> &'static str {
+    match state {
+        TestResult::Passed => "Passed",
+        TestResult::Failed(_) => "Failed",
+        TestResult::TimedOut => "TimedOut",
+        TestResult::OverBudget => "OverBudget",
+        TestResult::Ignored => "Ignored",
+    }
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(row: &Row, column: &str) -> rusqlite::Result<T> {
+    let json: String = row.get(column)?;
+    serde_json::from_str(&json).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_runner::store::aggregates_of;
+
+    fn in_memory() -> SqliteStore {
+        SqliteStore::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn migrations_are_applied_once() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        migrate(&mut connection).unwrap();
+        let version: usize = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn environments_are_stored_once() {
+        let mut store = in_memory();
+        store.save(&JsonResult::example(10, 100)).unwrap();
+        store.save(&JsonResult::example(20, 100)).unwrap();
+        let environments: i64 = store
+            .connection
+            .query_row("SELECT COUNT(*) FROM environments", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(environments, 1);
+    }
+
+    #[test]
+    fn processes_save_at_the_same_time() {
+        let directory =
+            std::env::temp_dir().join(format!("coppers-sqlite-busy-{}", std::process::id()));
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let directory = &directory;
+                scope.spawn(move || {
+                    let mut store = SqliteStore::open(directory).unwrap();
+                    for run in 0..10 {
+                        store
+                            .save(&JsonResult {
+                                run_id: format!("run-{thread}-{run}"),
+                                ..JsonResult::example(run, 100)
+                            })
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        let store = SqliteStore::open(&directory).unwrap();
+        assert_eq!(store.runs(&Query::default()).unwrap().len(), 40);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn migration_merges_duplicate_environments() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(MIGRATIONS[0]).unwrap();
+        connection.pragma_update(None, "user_version", 1).unwrap();
+        for id in [1, 2] {
+            connection
+                .execute(
+                    "INSERT INTO environments (id, hostname, rustc, target, profile, features,
+                        sensor) VALUES (?1, 'host', 'rustc', 'target', 'debug', '[]', 'rapl')",
+                    [id],
+                )
+                .unwrap();
+            connection
+                .execute(
+                    "INSERT INTO runs (run_id, invocation_id, merged_runs, execution_timestamp,
+                        environment, total_us, total_uj, overhead_us, overhead_uj,
+                        number_of_repeats, test_threads, outlier_detection, regressions,
+                        baseline_deviations)
+                        VALUES (?1, '', '[]', 0, ?2, 0, 0, 0, 0, 1, 1, '{}', '[]', '[]')",
+                    params![format!("run-{id}"), id],
+                )
+                .unwrap();
+        }
+
+        migrate(&mut connection).unwrap();
+        let environments: Vec<i64> = connection
+            .prepare("SELECT DISTINCT environment FROM runs")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(environments, [1]);
+        let duplicate = connection.execute(
+            "INSERT INTO environments (hostname, rustc, target, profile, features, sensor)
+                VALUES ('host', 'rustc', 'target', 'debug', '[]', 'rapl')",
+            [],
+        );
+        assert!(duplicate.is_err());
+    }
+
+    #[test]
+    fn runs_are_read_back() {
+        let mut store = in_memory();
+        let mut test = CompletedTest::empty("tests::a".to_string());
+        test.state = TestResult::Passed;
+        test.uj = Some(1000);
+        let run = JsonResult {
+            git: Some(GitInfo {
+                head: Some("4c9e1a0".to_string()),
+                commit_timestamp: Some(5),
+                branch: None,
+                dirty: true,
+                diff_hash: Some("8f14e45".to_string()),
+            }),
+            tests: vec![test],
+            ..JsonResult::example(10, 100)
+        };
+        store.save(&run).unwrap();
+        let other = Environment {
+            hostname: "other".to_string(),
+            ..Environment::default()
+        };
+        store
+            .save(&JsonResult {
+                environment: other.clone(),
+                ..JsonResult::example(20, 200)
+            })
+            .unwrap();
+        assert!(store.contains("run-10").unwrap());
+        assert!(!store.contains("run-30").unwrap());
+
+        let runs = store.runs(&Query::default()).unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].environment, other);
+        assert_eq!(
+            serde_json::to_value(&runs[1]).unwrap(),
+            serde_json::to_value(&run).unwrap()
+        );
+
+        let query = Query {
+            environment: Some(&Environment::default()),
+            limit: Some(5),
+        };
+        assert_eq!(store.runs(&query).unwrap().len(), 1);
+        let unknown = Environment {
+            hostname: "unknown".to_string(),
+            ..Environment::default()
+        };
+        let query = Query {
+            environment: Some(&unknown),
+            limit: None,
+        };
+        assert!(store.runs(&query).unwrap().is_empty());
+    }
+
+    #[test]
+    fn aggregates_match_the_runs() {
+        let mut store = in_memory();
+        for timestamp in [10, 20] {
+            let mut test = CompletedTest::empty("tests::a".to_string());
+            test.state = TestResult::Passed;
+            test.binary = Some("integration".to_string());
+            test.uj = Some(timestamp as u128 * 3);
+            test.us = Some(3);
+            test.counted_repeats = 3;
+            let failed = CompletedTest {
+                state: TestResult::Failed(None),
+                ..CompletedTest::empty("tests::b".to_string())
+            };
+            store
+                .save(&JsonResult {
+                    git: Some(GitInfo {
+                        head: Some("4c9e1a0".to_string()),
+                        commit_timestamp: Some(5),
+                        branch: None,
+                        dirty: false,
+                        diff_hash: None,
+                    }),
+                    tests: vec![test, failed],
+                    ..JsonResult::example(timestamp, 100)
+                })
+                .unwrap();
+        }
+
+        let query = Query {
+            environment: None,
+            limit: Some(1),
+        };
+        let aggregates = store.aggregates(&query).unwrap();
+        assert_eq!(aggregates.len(), 1);
+        assert_eq!(aggregates[0].run_id, "run-20");
+        assert_eq!(aggregates[0].uj, 20.0);
+        // The default implementation reads the complete runs instead
+        let from_runs: Vec<_> = store
+            .runs(&Query::default())
+            .unwrap()
+            .iter()
+            .flat_map(aggregates_of)
+            .collect();
+        assert_eq!(store.aggregates(&Query::default()).unwrap(), from_runs);
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2022 Thijs Raymakers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Where the results of all runs are kept. By default, every run is a JSON file in the results
+// directory, which means that every file has to be read to answer a query. With the `sqlite`
+// feature, the runs can be kept in a single SQLite database in the results directory instead.
+
+use super::environment::Environment;
+use super::json::{write_to_json, JsonResult};
+use crate::config::StoreKind;
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+pub(crate) trait ResultStore {
+    // Add the results of a run
+    fn save(&mut self, result: &JsonResult) -> Result<(), String>;
+    // The runs that match the query, starting with the latest one
+    fn runs(&self, query: &Query) -> Result<Vec<JsonResult>, String>;
+    fn contains(&self, run_id: &str) -> Result<bool, String>;
+    // The energy and time per iteration of the tests that ran completely in the runs that match
+    // the query, starting with the latest run. This is all the report needs to follow the tests
+    // over time, without the iterations of every test.
+    #[cfg_attr(not(feature = "visualization"), allow(dead_code))]
+    fn aggregates(&self, query: &Query) -> Result<Vec<TestAggregate>, String> {
+        Ok(self.runs(query)?.iter().flat_map(aggregates_of).collect())
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "visualization"), allow(dead_code))]
+pub(crate) struct TestAggregate {
+    pub(crate) run_id: String,
+    pub(crate) execution_timestamp: u64,
+    pub(crate) head: Option<String>,
+    pub(crate) commit_timestamp: Option<i64>,
+    pub(crate) dirty: bool,
+    pub(crate) binary: Option<String>,
+    pub(crate) name: String,
+    pub(crate) uj: f64,
+    pub(crate) us: f64,
+}
+
+#[derive(Default)]
+pub(crate) struct Query<'a> {
+    // Only the runs in this environment
+    pub(crate) environment: Option<&'a Environment>,
+    // The maximum amount of runs
+    pub(crate) limit: Option<usize>,
+}
+
+pub(crate) fn open_store(
+    kind: StoreKind,
+    directory: &Path,
+) -> Result<Box<dyn ResultStore>, String> {
+    match kind {
+        StoreKind::Json => Ok(Box::new(JsonStore::new(directory))),
+        #[cfg(feature = "sqlite")]
+        StoreKind::Sqlite => Ok(Box::new(super::sqlite::SqliteStore::open(directory)?)),
+        #[cfg(not(feature = "sqlite"))]
+        StoreKind::Sqlite => Err("Coppers is built without the `sqlite` feature".to_string()),
+    }
+}
+
+// Add the runs in the JSON files of the directory to the store, unless they are part of it
+// already. Returns the amount of runs that are added.
+pub(crate) fn import(directory: &Path, store: &mut dyn ResultStore) -> Result<usize, String> {
+    let mut imported = 0;
+    let mut runs = JsonStore::new(directory).runs(&Query::default())?;
+    // The oldest runs are added first, like they were originally
+    runs.reverse();
+    for run in runs {
+        if !store.contains(&run.run_id)? {
+            store.save(&run)?;
+            imported += 1;
+        }
+    }
+    Ok(imported)
+}
+
+pub(crate) struct JsonStore {
+    directory: PathBuf,
+}
+
+impl JsonStore {
+    pub(crate) fn new(directory: &Path) -> JsonStore {
+        JsonStore {
+            directory: directory.to_path_buf(),
+        }
+    }
+}
+
+impl ResultStore for JsonStore {
+    fn save(&mut self, result: &JsonResult) -> Result<(), String> {
+        write_to_json(result, &self.directory);
+        Ok(())
+    }
+
+    // Results that cannot be read are skipped
+    fn runs(&self, query: &Query) -> Result<Vec<JsonResult>, String> {
+        let Ok(entries) = read_dir(&self.directory) else {
+            return Ok(Vec::new());
+        };
+        let mut results: Vec<JsonResult> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                // Results in an older format are upgraded
+                let mut result = JsonResult::from_json(&read_to_string(&path).ok()?).ok()?;
+                // Runs from before run IDs existed are identified by their file instead
+                if result.run_id.is_empty() {
+                    result.run_id = run_id_of(&path)?;
+                }
+                Some(result)
+            })
+            .filter(|result| {
+                query
+                    .environment
+                    .is_none_or(|environment| result.environment == *environment)
+            })
+            .collect();
+        // Run IDs start with the milliseconds since the epoch, so they order the runs that are
+        // executed in the same second
+        results.sort_by(|a, b| {
+            (b.execution_timestamp, &b.run_id).cmp(&(a.execution_timestamp, &a.run_id))
+        });
+        if let Some(limit) = query.limit {
+            results.truncate(limit);
+        }
+        Ok(results)
+    }
+
+    fn contains(&self, run_id: &str) -> Result<bool, String> {
+        Ok(self
+            .directory
+            .join(format!("coppers_results-{run_id}.json"))
+            .is_file())
+    }
+}
+
+#[cfg_attr(not(feature = "visualization"), allow(dead_code))]
+pub(super) fn aggregates_of(run: &JsonResult) -> impl Iterator<Item = TestAggregate> + '_ {
+    let git = run.git.as_ref();
+    run.tests.iter().filter_map(move |test| {
+        Some(TestAggregate {
+            run_id: run.run_id.clone(),
+            execution_timestamp: run.execution_timestamp,
+            head: git.and_then(|git| git.head.clone()),
+            commit_timestamp: git.and_then(|git| git.commit_timestamp),
+            dirty: git.is_some_and(|git| git.dirty),
+            binary: test.binary.clone(),
+            name: test.name.clone(),
+            uj: test.average_uj()?,
+            us: test.average_us()?,
+        })
+    })
+}
+
+fn run_id_of(path: &Path) -> Option<String> {
+    let name = path.file_stem()?.to_str()?;
+    Some(
+        name.strip_prefix("coppers_results-")
+            .unwrap_or(name)
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_runner::{CompletedTest, TestResult};
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn json_store_queries() {
+        let directory =
+            std::env::temp_dir().join(format!("coppers-json-store-{}", std::process::id()));
+        let mut store = JsonStore::new(&directory);
+        assert!(store.runs(&Query::default()).unwrap().is_empty());
+
+        let other = Environment {
+            hostname: "other".to_string(),
+            ..Environment::default()
+        };
+        store.save(&JsonResult::example(10, 100)).unwrap();
+        store.save(&JsonResult::example(30, 300)).unwrap();
+        store
+            .save(&JsonResult {
+                environment: other.clone(),
+                ..JsonResult::example(20, 200)
+            })
+            .unwrap();
+        assert!(store.contains("run-10").unwrap());
+        assert!(!store.contains("run-40").unwrap());
+
+        let latest = store
+            .runs(&Query {
+                environment: Some(&Environment::default()),
+                limit: Some(1),
+            })
+            .unwrap();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].run_id, "run-30");
+        let all = store.runs(&Query::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[1].environment, other);
+        remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn aggregates_of_completed_tests() {
+        let mut run = JsonResult::example(10, 100);
+        let mut passed = CompletedTest::empty("tests::a".to_string());
+        passed.state = TestResult::Passed;
+        passed.uj = Some(30);
+        passed.us = Some(6);
+        passed.counted_repeats = 3;
+        run.tests = vec![passed, CompletedTest::empty("tests::b".to_string())];
+
+        let aggregates: Vec<_> = aggregates_of(&run).collect();
+        assert_eq!(aggregates.len(), 1);
+        assert_eq!(aggregates[0].run_id, "run-10");
+        assert_eq!(aggregates[0].name, "tests::a");
+        assert_eq!(aggregates[0].uj, 10.0);
+        assert_eq!(aggregates[0].us, 2.0);
+    }
+
+    #[test]
+    fn json_store_orders_runs_in_the_same_second() {
+        let directory =
+            std::env::temp_dir().join(format!("coppers-json-store-order-{}", std::process::id()));
+        let mut store = JsonStore::new(&directory);
+        for run_id in ["1650000000900-2-b", "1650000000100-1-a"] {
+            store
+                .save(&JsonResult {
+                    run_id: run_id.to_string(),
+                    ..JsonResult::example(1650000000, 100)
+                })
+                .unwrap();
+        }
+        let runs = store.runs(&Query::default()).unwrap();
+        assert_eq!(runs[0].run_id, "1650000000900-2-b");
+        remove_dir_all(directory).unwrap();
+    }
+}
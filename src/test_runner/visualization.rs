@@ -12,7 +12,11 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
+use super::json::JsonResult;
+use super::location::report_directory;
+use super::store::{Query, ResultStore};
 use pyo3::prelude::*;
+use std::path::Path;
 
 const PYTHON_CODE: &str = r#"
 import json
@@ -64,7 +68,7 @@ Coppers test energy usage</h1>
 
         <h2>Most energy consuming tests</h2>
         <ul class="list-group list-group-numbered">
-            {%- for i in range(3) %}
+            {%- for i in range(amount_top) %}
               <li class="list-group-item d-flex justify-content-between align-items-start">
                 <div class="ms-2 me-auto">
                   {{ most_energy_consuming_names[i] }}
@@ -110,64 +114,63 @@ Coppers test energy usage</h1>
 """
 
 AMOUNT_OF_TESTS_IN_TOP = 3
-RESULT_PATH = "target/coppers_results"
 
 
-def is_coppers_file(filename):
-    return ".json" in filename and "coppers_results" in filename
+def passed_tests(result):
+    # Failed and ignored tests are part of the results as well, but their energy consumption
+    # is not comparable with that of the passed tests. Tests that are over their budget did run
+    # completely, so they are included. Tests of runs where only time is measured have no energy.
+    return [test for test in result["tests"]
+            if test["state"] in ("Passed", "OverBudget") and test.get("uj") is not None]
+
+
+def display_name(test):
+    # Tests of different test binaries can have the same name
+    binary = test.get("binary")
+    return f"{binary}::{test['name']}" if binary else test["name"]
+
 
+def same_test(a, b):
+    # Older results do not know which binary a test is part of
+    return a["name"] == b["name"] and (not a.get("binary") or not b.get("binary") or a["binary"] == b["binary"])
 
-def get_data():
-    last_execution_filename = ""
-    last_execution_timestamp = 0
-    for filename in os.listdir(f"{RESULT_PATH}"):
-        if is_coppers_file(filename):
-            with open(f"{RESULT_PATH}/{filename}", "r") as f:
-                result = json.load(f)
-                if int(result["execution_timestamp"]) > int(last_execution_timestamp):
-                    last_execution_filename = filename
-                    last_execution_timestamp = result["execution_timestamp"]
 
-    with open(f"{RESULT_PATH}/{last_execution_filename}", "r") as f:
-        results = json.load(f)
-        return results
+# The commit that a run is executed on. Runs without git information do not have a commit at all.
+def commit_of(aggregate):
+    head = (aggregate.get("head") or "")[:7] or "no commit"
+    if aggregate.get("dirty"):
+        head += "-dirty"
+    return head, aggregate.get("commit_timestamp") or 0
 
 
-def visualize_all_tests(data, n):
+def visualize_all_tests(data):
     data = sorted(data, reverse=True, key=lambda item: item["uj"])
-    bars = [round(test["uj"] / n) for test in reversed(data)]
-    x = [test["name"] for test in reversed(data)]
+    bars = [round(test["uj"] / test["counted_repeats"]) for test in reversed(data)]
+    x = [display_name(test) for test in reversed(data)]
     fig = px.bar(x=bars, y=x, labels={"x": "Energy consumption (\u03bcJ)", "y": "Test"})
     return plotly.io.to_html(fig)
 
 
-def visualize_over_time():
-    all_runs = pd.DataFrame()
-    for filename in os.listdir(f"{RESULT_PATH}"):
-        if is_coppers_file(filename):
-            with open(f"{RESULT_PATH}/{filename}", "r") as f:
-                result = json.load(f)
-                new_res = pd.json_normalize(result, record_path="tests",
-                                            meta=["execution_timestamp", "commit_timestamp", "head"])
-                n = float(result["number_of_repeats"])
-                new_res['uj'] = new_res['uj'] / n
-                new_res['us'] = new_res['uj'] / n
-                all_runs = pd.concat([all_runs, new_res], axis=0)
-    all_runs = all_runs.sort_values(by="execution_timestamp", kind="mergesort")
+def visualize_over_time(aggregates):
+    all_runs = pd.DataFrame(aggregates)
+    all_runs["name"] = [display_name(aggregate) for aggregate in aggregates]
+    commits = [commit_of(aggregate) for aggregate in aggregates]
+    all_runs["head"] = [head for head, _ in commits]
+    all_runs["commit_timestamp"] = [timestamp for _, timestamp in commits]
+    all_runs = all_runs.sort_values(by=["execution_timestamp", "run_id"], kind="mergesort")
     all_runs = all_runs.sort_values(by="commit_timestamp", kind="mergesort")
 
     i = -1
-    last_timestamp = 0
-    last_timestamp = 0
+    last_run = None
     sequential_index = []
     tick_vals = []
     tick_texts = []
     for test in all_runs.iterrows():
-        if last_timestamp != test[1]["execution_timestamp"]:
+        if last_run != test[1]["run_id"]:
             i += 1
-            last_timestamp = test[1]["execution_timestamp"]
+            last_run = test[1]["run_id"]
             tick_vals.append(i)
-            text = test[1]["head"][:7]
+            text = test[1]["head"]
             text = text + f" executed at {datetime.fromtimestamp(test[1]['execution_timestamp'])}"
             tick_texts.append(text)
         sequential_index.append(i)
@@ -188,45 +191,35 @@ def visualize_over_time():
     return plotly.io.to_html(fig)
 
 
-def comparison_to_last(data):
-    last_execution_filename = ""
-    last_execution_timestamp = 0
-    n = float(data["number_of_repeats"])
-    for filename in os.listdir(f"{RESULT_PATH}"):
-        if is_coppers_file(filename):
-            with open(f"{RESULT_PATH}/{filename}", "r") as f:
-                result = json.load(f)
-                if (result["execution_timestamp"] > last_execution_timestamp) and (result["execution_timestamp"] < data[
-                    "execution_timestamp"]):
-                    last_execution_filename = filename
-                    last_execution_timestamp = result["execution_timestamp"]
+def percentage(change, before):
+    return round(change / before * 100, 1) if before else None
 
 
-    with open(f"{RESULT_PATH}/{last_execution_filename}", "r") as f:
-        last_result = json.load(f)
-    change_overall = round(data["total_uj"] / n - last_result["total_uj"] / n)
+def comparison_to_last(data, last_result):
+    change_overall = round(data["total_uj"] - last_result["total_uj"])
 
     comparison_data = []
-    for test in data["tests"]:
-        tests_before = [t for t in last_result["tests"] if t["name"] == test["name"]]
+    for test in passed_tests(data):
+        tests_before = [t for t in passed_tests(last_result) if same_test(t, test)]
         if len(tests_before) > 0:
             test_before = tests_before[0]
-            n_before = last_result["number_of_repeats"]
-            test["uj"] = test["uj"] / n
-            test["us"] = test["us"] / n
+            n_before = test_before["counted_repeats"]
+            n_test = test["counted_repeats"]
+            test["uj"] = test["uj"] / n_test
+            test["us"] = test["us"] / n_test
             test_before["uj"] = test_before["uj"] / n_before
             test_before["us"] = test_before["us"] / n_before
 
             comparison_data.append([
-                test["name"],
+                display_name(test),
                 round(test_before["uj"]),
                 round(test["uj"]),
                 round(test["uj"] - test_before["uj"]),
-                round((test["uj"] - test_before["uj"]) / test_before["uj"] * 100, 1),
+                percentage(test["uj"] - test_before["uj"], test_before["uj"]),
                 round(test_before["us"]),
                 round(test["us"]),
                 round(test["us"] - test_before["us"]),
-                round((test["us"] - test_before["us"]) / test_before["us"] * 100, 1),
+                percentage(test["us"] - test_before["us"], test_before["us"]),
 
             ])
 
@@ -236,54 +229,80 @@ def comparison_to_last(data):
     return change_overall, df.to_html(justify='left')
 
 
-def main():
+# The latest run, the previous run in the same environment if there is one, and the energy per
+# iteration of the tests in all runs in the same environment are queried from the store by Coppers, and passed as JSON
+def main(latest, previous, aggregates, report_folder):
     template = Environment(loader=BaseLoader).from_string(TEMPLATE)
     jinja = {}
 
-    results = get_data()
-    amount_of_results = len([filename for filename in os.listdir(RESULT_PATH) if "json" in filename])
+    results = json.loads(latest)
+    aggregates = json.loads(aggregates)
 
-    if amount_of_results > 2:
+    if len({aggregate["run_id"] for aggregate in aggregates}) > 2:
         jinja['over_time'] = True
-        jinja['plot_energy_over_time'] = visualize_over_time()
-
-    sorted_tests = sorted(get_data()["tests"], reverse=True, key=lambda item: item["uj"])
-    n = float(results["number_of_repeats"])
-    jinja['amount_top'] = AMOUNT_OF_TESTS_IN_TOP
-    jinja['most_energy_consuming_names'] = [sorted_tests[i]['name'] for i in range(AMOUNT_OF_TESTS_IN_TOP)]
-    jinja['most_energy_consuming_usages'] = [round(sorted_tests[i]['uj'] / n) for i in range(AMOUNT_OF_TESTS_IN_TOP)]
-    jinja['least_energy_consuming_names'] = [sorted_tests[-(i + 1)]['name'] for i in range(AMOUNT_OF_TESTS_IN_TOP)]
-    jinja['least_energy_consuming_usages'] = [round(sorted_tests[-(i + 1)]['uj'] / n) for i in range(AMOUNT_OF_TESTS_IN_TOP)]
-
-    if amount_of_results > 1:
+        jinja['plot_energy_over_time'] = visualize_over_time(aggregates)
+
+    # The comparison changes the tests, so they are read again
+    sorted_tests = sorted(passed_tests(json.loads(latest)), reverse=True, key=lambda item: item["uj"])
+    amount_top = min(AMOUNT_OF_TESTS_IN_TOP, len(sorted_tests))
+    jinja['amount_top'] = amount_top
+    jinja['most_energy_consuming_names'] = [display_name(sorted_tests[i]) for i in range(amount_top)]
+    jinja['most_energy_consuming_usages'] = [round(sorted_tests[i]['uj'] / sorted_tests[i]['counted_repeats']) for i in range(amount_top)]
+    jinja['least_energy_consuming_names'] = [display_name(sorted_tests[-(i + 1)]) for i in range(amount_top)]
+    jinja['least_energy_consuming_usages'] = [round(sorted_tests[-(i + 1)]['uj'] / sorted_tests[-(i + 1)]['counted_repeats']) for i in range(amount_top)]
+
+    comparison = comparison_to_last(results, json.loads(previous)) if previous is not None else None
+    if comparison is not None:
         jinja["compare_to_last"] = True
-        jinja["overall_change"], jinja["comparison_table"] = comparison_to_last(results)
+        jinja["overall_change"], jinja["comparison_table"] = comparison
 
-    jinja['all_tests_plot'] = visualize_all_tests(sorted_tests, n)
+    jinja['all_tests_plot'] = visualize_all_tests(sorted_tests)
 
-    report_folder = "target/coppers_report"
     generate_report(template, jinja, report_folder)
     print(f"> Generated report of energy consumption results in \"{report_folder}\"")
 
 
 def generate_report(template, jinja, report_folder):
-    if not os.path.isdir(report_folder):
-        os.mkdir(report_folder)
-
-    if not os.path.isdir(f"{report_folder}/images"):
-        os.mkdir(f"{report_folder}/images")
+    os.makedirs(f"{report_folder}/images", exist_ok=True)
 
     with open(f"{report_folder}/index.html", "w") as fh:
         data = template.render(jinja)
         fh.write(data)
-
-main()
 "#;
 
-pub fn visualize() {
+// Generate the report of the current run and the runs in the store. The current run is passed
+// explicitly, because other test binaries might have stored a run at the same time. The report
+// is written next to the results directory.
+pub fn visualize(
+    store: &dyn ResultStore,
+    latest: &JsonResult,
+    results_directory: &Path,
+) -> Result<(), String> {
+    // Only the energy per iteration of the tests is needed of all runs in the same environment,
+    // which stays small even after thousands of runs
+    let aggregates = store.aggregates(&Query {
+        environment: Some(&latest.environment),
+        limit: None,
+    })?;
+    // The current run is stored already, so it is one of the two latest runs
+    let previous = store
+        .runs(&Query {
+            environment: Some(&latest.environment),
+            limit: Some(2),
+        })?
+        .into_iter()
+        .find(|run| run.run_id != latest.run_id);
+    let to_json = |run: &JsonResult| serde_json::to_string(run).unwrap();
+    let report_directory = report_directory(results_directory);
     Python::with_gil(|py| -> PyResult<()> {
-        PyModule::from_code(py, PYTHON_CODE, "visualization.py", "visualization")?;
+        let module = PyModule::from_code(py, PYTHON_CODE, "visualization.py", "visualization")?;
+        module.getattr("main")?.call1((
+            to_json(latest),
+            previous.as_ref().map(to_json),
+            serde_json::to_string(&aggregates).unwrap(),
+            report_directory.to_string_lossy(),
+        ))?;
         Ok(())
     })
-    .unwrap();
+    .map_err(|e| format!("Could not generate the report: {e}"))
 }
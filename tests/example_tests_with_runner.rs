@@ -1,5 +1,6 @@
-#![feature(custom_test_frameworks)]
-#![test_runner(coppers::runner)]
+// Without a nightly compiler, these tests run with the default test harness instead
+#![cfg_attr(coppers_nightly, feature(custom_test_frameworks))]
+#![cfg_attr(coppers_nightly, test_runner(coppers::runner))]
 
 #[test]
 fn test_succesful_test() {
@@ -13,7 +14,7 @@ fn test_ignored_test() {
 }
 
 #[test]
-#[should_panic(expected = "assertion failed")]
+#[should_panic(expected = "assertion `left == right` failed")]
 fn test_should_panic_with_expected_message() {
     assert_eq!(1 + 1, 3);
 }
@@ -23,3 +24,8 @@ fn test_should_panic_with_expected_message() {
 fn test_should_panic() {
     assert_eq!(1 + 1, 3);
 }
+
+#[coppers::test(repeats = 5, warmup = 1, group = "arithmetic")]
+fn test_with_coppers_settings() {
+    assert_eq!(2 * 21, 42);
+}
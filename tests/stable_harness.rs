@@ -0,0 +1,28 @@
+// This test target does not use libtest, so it runs on a stable compiler as well. Only the
+// tests that are marked with `#[coppers::test]` are run.
+
+fn main() {
+    coppers::main()
+}
+
+#[coppers::test]
+fn test_succesful_test() {
+    assert_eq!(1 + 1, 2);
+}
+
+#[coppers::test]
+#[ignore]
+fn test_ignored_test() {
+    assert_eq!(5 * 10, 5)
+}
+
+#[coppers::test]
+#[should_panic(expected = "assertion `left == right` failed")]
+fn test_should_panic_with_expected_message() {
+    assert_eq!(1 + 1, 3);
+}
+
+#[coppers::test(repeats = 5, warmup = 1)]
+fn test_returns_result() -> Result<(), String> {
+    "42".parse::<u32>().map(|_| ()).map_err(|e| e.to_string())
+}